@@ -1,7 +1,9 @@
 // module schema
 
+use crate::store::filter::SearchFilter;
 use clap::Parser;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// rust-container-tool cli struct
 #[derive(Parser, Debug)]
@@ -23,6 +25,42 @@ pub struct Cli {
     #[arg(short, long, value_name = "chat-client", default_value = "false")]
     pub chat_client: bool,
 
+    /// only re-embed new or changed files (keeps the existing collection).
+    #[arg(short, long, value_name = "incremental", default_value = "false")]
+    pub incremental: bool,
+
+    /// keep watching the docs folder(s) and sync the collection(s) on changes.
+    #[arg(short, long, value_name = "watch", default_value = "false")]
+    pub watch: bool,
+
+    /// load and chunk the files without touching qdrant or embedding anything.
+    #[arg(short, long, value_name = "dry-run", default_value = "false")]
+    pub dry_run: bool,
+
+    /// continue an interrupted index run from its checkpoint.
+    #[arg(short, long, value_name = "resume", default_value = "false")]
+    pub resume: bool,
+
+    /// don't use the embedding cache.
+    #[arg(long, value_name = "no-cache", default_value = "false")]
+    pub no_cache: bool,
+
+    /// print the embedding cache size and entries per model.
+    #[arg(long, value_name = "cache-stats", default_value = "false")]
+    pub cache_stats: bool,
+
+    /// remove cache entries for other embedding models (and older than cacheMaxAgeDays).
+    #[arg(long, value_name = "cache-prune", default_value = "false")]
+    pub cache_prune: bool,
+
+    /// print every file, the chunks it produces and the text that would be embedded.
+    #[arg(long, value_name = "inspect", default_value = "false")]
+    pub inspect: bool,
+
+    /// switch every category back to its previous collection version.
+    #[arg(long, value_name = "rollback", default_value = "false")]
+    pub rollback: bool,
+
     /// set the user prompt (used for debugging).
     #[arg(short, long, value_name = "user-prompt", default_value = "")]
     pub user_prompt: Option<String>,
@@ -56,6 +94,26 @@ pub struct Spec {
     pub qdrant_url: String,
     #[serde(rename = "qdrantPort")]
     pub qdrant_port: i32,
+    #[serde(rename = "vectorStore")]
+    pub vector_store: Option<VectorStoreKind>,
+    #[serde(rename = "vectorStorePath")]
+    pub vector_store_path: Option<String>,
+    #[serde(rename = "distance")]
+    pub distance: Option<DistanceKind>,
+    #[serde(rename = "hnswM")]
+    pub hnsw_m: Option<u64>,
+    #[serde(rename = "hnswEfConstruct")]
+    pub hnsw_ef_construct: Option<u64>,
+    #[serde(rename = "hnswEf")]
+    pub hnsw_ef: Option<u64>,
+    #[serde(rename = "quantization")]
+    pub quantization: Option<QuantizationKind>,
+    #[serde(rename = "quantizationAlwaysRam")]
+    pub quantization_always_ram: Option<bool>,
+    #[serde(rename = "onDisk")]
+    pub on_disk: Option<bool>,
+    #[serde(rename = "float16")]
+    pub float16: Option<bool>,
     #[serde(rename = "category")]
     pub category: String,
     #[serde(rename = "kbDocsPath")]
@@ -64,6 +122,46 @@ pub struct Spec {
     pub server_port: u16,
     #[serde(rename = "embeddingModel")]
     pub embedding_model: String,
+    #[serde(rename = "embeddingProvider")]
+    pub embedding_provider: Option<EmbeddingProviderKind>,
+    #[serde(rename = "queryPrefix")]
+    pub query_prefix: Option<String>,
+    #[serde(rename = "documentPrefix")]
+    pub document_prefix: Option<String>,
+    #[serde(rename = "autoPrefixes")]
+    pub auto_prefixes: Option<bool>,
+    #[serde(rename = "normalizeEmbeddings")]
+    pub normalize_embeddings: Option<bool>,
+    #[serde(rename = "embeddingDimensions")]
+    pub embedding_dimensions: Option<usize>,
+    #[serde(rename = "hybridSearch")]
+    pub hybrid_search: Option<bool>,
+    #[serde(rename = "denseWeight")]
+    pub dense_weight: Option<f32>,
+    #[serde(rename = "sparseWeight")]
+    pub sparse_weight: Option<f32>,
+    #[serde(rename = "rrfK")]
+    pub rrf_k: Option<f32>,
+    #[serde(rename = "bm25AvgLength")]
+    pub bm25_avg_length: Option<f32>,
+    #[serde(rename = "hybridScoreThreshold")]
+    pub hybrid_score_threshold: Option<f32>,
+    #[serde(rename = "metadataMismatch")]
+    pub metadata_mismatch: Option<MismatchAction>,
+    #[serde(rename = "embeddingModelPath")]
+    pub embedding_model_path: Option<String>,
+    #[serde(rename = "embeddingApiKey")]
+    pub embedding_api_key: Option<String>,
+    #[serde(rename = "embeddingConnectTimeoutMs")]
+    pub embedding_connect_timeout_ms: Option<u64>,
+    #[serde(rename = "embeddingTimeoutMs")]
+    pub embedding_timeout_ms: Option<u64>,
+    #[serde(rename = "embeddingRetries")]
+    pub embedding_retries: Option<u32>,
+    #[serde(rename = "embeddingRetryBackoffMs")]
+    pub embedding_retry_backoff_ms: Option<u64>,
+    #[serde(rename = "embeddingRateLimit")]
+    pub embedding_rate_limit: Option<f64>,
     #[serde(rename = "servingModel")]
     pub serving_model: String,
     #[serde(rename = "scoreThreshold")]
@@ -74,6 +172,223 @@ pub struct Spec {
     pub file_extension: String,
     #[serde(rename = "headerRegex")]
     pub header_regex: Option<String>,
+    #[serde(rename = "headerBlock")]
+    pub header_block: Option<bool>,
     #[serde(rename = "searchLimit")]
     pub search_limit: u64,
+    #[serde(rename = "searchFilter")]
+    pub search_filter: Option<SearchFilter>,
+    #[serde(rename = "upsertBatchSize")]
+    pub upsert_batch_size: Option<usize>,
+    #[serde(rename = "embeddingWorkers")]
+    pub embedding_workers: Option<usize>,
+    #[serde(rename = "embeddingBatchSize")]
+    pub embedding_batch_size: Option<usize>,
+    #[serde(rename = "chunkStrategy")]
+    pub chunk_strategy: Option<ChunkStrategy>,
+    #[serde(rename = "chunkStrategies")]
+    pub chunk_strategies: Option<HashMap<String, ChunkStrategy>>,
+    #[serde(rename = "include")]
+    pub include: Option<Vec<String>>,
+    #[serde(rename = "exclude")]
+    pub exclude: Option<Vec<String>>,
+    #[serde(rename = "chunkSize")]
+    pub chunk_size: Option<usize>,
+    #[serde(rename = "chunkOverlap")]
+    pub chunk_overlap: Option<usize>,
+    #[serde(rename = "maxTokens")]
+    pub max_tokens: Option<usize>,
+    #[serde(rename = "docsFolder")]
+    pub docs_folder: Option<String>,
+    #[serde(rename = "embeddingCachePath")]
+    pub embedding_cache_path: Option<String>,
+    #[serde(rename = "cacheMaxAgeDays")]
+    pub cache_max_age_days: Option<u64>,
+    #[serde(rename = "keepVersions")]
+    pub keep_versions: Option<usize>,
+    #[serde(rename = "checkpointDir")]
+    pub checkpoint_dir: Option<String>,
+    #[serde(rename = "watchDebounceMs")]
+    pub watch_debounce_ms: Option<u64>,
+    #[serde(rename = "categories")]
+    pub categories: Option<Vec<CategoryConfig>>,
+}
+
+/// Per category settings, unset fields fall back to the spec values
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CategoryConfig {
+    #[serde(rename = "name")]
+    pub name: String,
+    #[serde(rename = "docsFolder")]
+    pub docs_folder: Option<String>,
+    #[serde(rename = "fileExtension")]
+    pub file_extension: Option<String>,
+    #[serde(rename = "useHeaders")]
+    pub use_headers: Option<bool>,
+    #[serde(rename = "headerRegex")]
+    pub header_regex: Option<String>,
+    #[serde(rename = "headerBlock")]
+    pub header_block: Option<bool>,
+    #[serde(rename = "chunkStrategy")]
+    pub chunk_strategy: Option<ChunkStrategy>,
+    #[serde(rename = "chunkStrategies")]
+    pub chunk_strategies: Option<HashMap<String, ChunkStrategy>>,
+    #[serde(rename = "include")]
+    pub include: Option<Vec<String>>,
+    #[serde(rename = "exclude")]
+    pub exclude: Option<Vec<String>>,
+    #[serde(rename = "chunkSize")]
+    pub chunk_size: Option<usize>,
+    #[serde(rename = "chunkOverlap")]
+    pub chunk_overlap: Option<usize>,
+}
+
+/// How source files are split into chunks for embedding
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkStrategy {
+    /// fixed size word windows over the whole file
+    #[default]
+    Words,
+    /// embed only the header line(s) found with headerRegex
+    Headers,
+    /// one chunk per markdown #, ## and ### section
+    Sections,
+}
+
+/// Embedding backend, the servers are reached at llamacppEmbeddingUrl:llamacppEmbeddingPort
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmbeddingProviderKind {
+    /// llama.cpp server native /embedding endpoint
+    #[default]
+    LlamaCpp,
+    /// OpenAI compatible /v1/embeddings endpoint
+    OpenAI,
+    /// Ollama /api/embed endpoint
+    Ollama,
+    /// in process cpu model loaded from embeddingModelPath (local-embeddings feature)
+    Local,
+}
+
+/// Where the embedded chunks are stored and searched
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VectorStoreKind {
+    /// qdrant server at qdrantUrl:qdrantPort
+    #[default]
+    Qdrant,
+    /// local file at vectorStorePath, brute force search, no server needed
+    Embedded,
+}
+
+/// Distance metric of the dense vectors
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DistanceKind {
+    #[default]
+    Cosine,
+    Dot,
+    /// euclidean distance, lower scores are closer
+    Euclid,
+    /// manhattan distance, lower scores are closer
+    Manhattan,
+}
+
+/// Compression of the dense vectors held in memory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuantizationKind {
+    /// int8 per dimension, about 4x smaller
+    Scalar,
+    /// one bit per dimension, 32x smaller, for high dimension cosine models
+    Binary,
+}
+
+/// What to do when a collection was indexed with other embedding settings
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MismatchAction {
+    /// log the differences and carry on
+    Warn,
+    /// stop with an error
+    #[default]
+    Abort,
+}
+
+impl DistanceKind {
+    /// true when the search scores are distances instead of similarities
+    pub fn lower_is_closer(&self) -> bool {
+        matches!(self, DistanceKind::Euclid | DistanceKind::Manhattan)
+    }
+}
+
+impl Spec {
+    /// base url of the embedding server
+    pub fn embedding_server(&self) -> String {
+        format!(
+            "{}:{}",
+            self.llamacpp_embedding_url, self.llamacpp_embedding_port
+        )
+    }
+
+    /// server used to count tokens, only llama.cpp exposes /tokenize
+    pub fn tokenizer_server(&self) -> Option<String> {
+        match self.embedding_provider.clone().unwrap_or_default() {
+            EmbeddingProviderKind::LlamaCpp => Some(self.embedding_server()),
+            _ => None,
+        }
+    }
+
+    /// only warn when the collection metadata doesn't match the settings
+    pub fn warn_on_mismatch(&self) -> bool {
+        self.metadata_mismatch.clone().unwrap_or_default() == MismatchAction::Warn
+    }
+
+    /// folder holding the docs for the category, defaults to kbDocsPath/category
+    pub fn docs_folder(&self) -> String {
+        self.docs_folder
+            .clone()
+            .unwrap_or(format!("{}/{}", self.kb_docs_path, self.category))
+    }
+
+    /// one spec per category to index, the spec itself when no categories are listed
+    pub fn categories(&self) -> Vec<Spec> {
+        let Some(categories) = self.categories.as_ref() else {
+            return vec![self.clone()];
+        };
+        categories
+            .iter()
+            .map(|category| {
+                let mut spec = self.clone();
+                spec.category = category.name.clone();
+                spec.docs_folder = category.docs_folder.clone();
+                spec.categories = None;
+                if let Some(file_extension) = category.file_extension.clone() {
+                    spec.file_extension = file_extension;
+                }
+                if let Some(use_headers) = category.use_headers {
+                    spec.use_headers = use_headers;
+                }
+                spec.header_regex = category.header_regex.clone().or(spec.header_regex);
+                spec.header_block = category.header_block.or(spec.header_block);
+                spec.chunk_strategy = category.chunk_strategy.clone().or(spec.chunk_strategy);
+                spec.chunk_strategies = category.chunk_strategies.clone().or(spec.chunk_strategies);
+                spec.include = category.include.clone().or(spec.include);
+                spec.exclude = category.exclude.clone().or(spec.exclude);
+                spec.chunk_size = category.chunk_size.or(spec.chunk_size);
+                spec.chunk_overlap = category.chunk_overlap.or(spec.chunk_overlap);
+                spec
+            })
+            .collect()
+    }
+
+    /// chunk strategy, defaults to headers or words based on useHeaders
+    pub fn strategy(&self) -> ChunkStrategy {
+        match &self.chunk_strategy {
+            Some(strategy) => strategy.clone(),
+            None if self.use_headers => ChunkStrategy::Headers,
+            None => ChunkStrategy::Words,
+        }
+    }
 }
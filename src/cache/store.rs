@@ -0,0 +1,183 @@
+use crate::markdown::process::content_hash;
+use custom_logger as log;
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// key "<embedding model>:<sha256 of text>", value created time (u64 le) followed by the f32 (le) vector
+const EMBEDDINGS: TableDefinition<&str, &[u8]> = TableDefinition::new("embeddings");
+
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub bytes: u64,
+    // entries per embedding model
+    pub models: BTreeMap<String, u64>,
+}
+
+// On-disk embedding cache keyed by (embedding model, text hash)
+pub struct EmbeddingCache {
+    db: Database,
+    path: String,
+}
+
+impl EmbeddingCache {
+    pub fn open(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let db = Database::create(path)?;
+        // make sure the table exists for readers
+        let txn = db.begin_write()?;
+        txn.open_table(EMBEDDINGS)?;
+        txn.commit()?;
+        Ok(Self {
+            db,
+            path: path.to_string(),
+        })
+    }
+
+    fn key(model: &str, text: &str) -> String {
+        format!("{}:{}", model, content_hash(text))
+    }
+
+    pub fn get(
+        &self,
+        model: &str,
+        text: &str,
+    ) -> Result<Option<Vec<f32>>, Box<dyn std::error::Error>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(EMBEDDINGS)?;
+        let value = table.get(Self::key(model, text).as_str())?;
+        Ok(value.map(|v| decode(v.value())))
+    }
+
+    pub fn put(
+        &self,
+        model: &str,
+        text: &str,
+        embedding: &[f32],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut value = now.to_le_bytes().to_vec();
+        for v in embedding.iter() {
+            value.extend_from_slice(&v.to_le_bytes());
+        }
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(EMBEDDINGS)?;
+            table.insert(Self::key(model, text).as_str(), value.as_slice())?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<CacheStats, Box<dyn std::error::Error>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(EMBEDDINGS)?;
+        let mut stats = CacheStats {
+            entries: table.len()?,
+            bytes: fs::metadata(&self.path)?.len(),
+            ..Default::default()
+        };
+        for entry in table.iter()? {
+            let (key, _) = entry?;
+            let model = model_of(key.value());
+            *stats.models.entry(model.to_string()).or_insert(0) += 1;
+        }
+        Ok(stats)
+    }
+
+    // remove entries for other embedding models and, when max_age_days is set,
+    // entries older than that. Returns the number of entries removed
+    pub fn prune(
+        &mut self,
+        model: &str,
+        max_age_days: Option<u64>,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let oldest = max_age_days.map_or(0, |days| now.saturating_sub(days * 24 * 60 * 60));
+        let txn = self.db.begin_write()?;
+        let removed;
+        {
+            let mut table = txn.open_table(EMBEDDINGS)?;
+            let before = table.len()?;
+            table.retain(|key, value| model_of(key) == model && created(value) >= oldest)?;
+            removed = before - table.len()?;
+        }
+        txn.commit()?;
+        self.db.compact()?;
+        log::info!("pruned {} cache entries", removed);
+        Ok(removed)
+    }
+}
+
+// Print the cache size and entries per model, when prune is set entries for
+// other models (and older than the given max age in days) are removed first
+pub fn print_cache_stats(
+    path: &str,
+    model: &str,
+    prune: bool,
+    max_age_days: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cache = EmbeddingCache::open(path)?;
+    if prune {
+        let removed = cache.prune(model, max_age_days)?;
+        println!("pruned  : {}", removed);
+    }
+    let stats = cache.stats()?;
+    println!("cache   : {}", path);
+    println!("entries : {}", stats.entries);
+    println!("size    : {} bytes", stats.bytes);
+    for (model, count) in stats.models.iter() {
+        println!("  {} : {}", model, count);
+    }
+    Ok(())
+}
+
+// the model part of a key, models may contain ':' so split on the last one
+fn model_of(key: &str) -> &str {
+    key.rsplit_once(':').map_or(key, |(model, _)| model)
+}
+
+fn created(value: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&value[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn decode(value: &[u8]) -> Vec<f32> {
+    value[8..]
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn embedding_cache_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("embeddings.redb");
+        let mut cache = EmbeddingCache::open(path.to_str().unwrap()).unwrap();
+        let model = "second-state/All-MiniLM-L6-v2-Embedding-GGUF:Q5_K_S";
+
+        assert_eq!(cache.get(model, "hello").unwrap(), None);
+        cache.put(model, "hello", &[0.5, -1.25]).unwrap();
+        cache.put("other-model", "hello", &[1.0]).unwrap();
+        assert_eq!(cache.get(model, "hello").unwrap(), Some(vec![0.5, -1.25]));
+
+        let stats = cache.stats().unwrap();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.models[model], 1);
+
+        assert_eq!(cache.prune(model, None).unwrap(), 1);
+        assert_eq!(cache.get("other-model", "hello").unwrap(), None);
+        assert_eq!(cache.get(model, "hello").unwrap(), Some(vec![0.5, -1.25]));
+    }
+}
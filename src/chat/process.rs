@@ -1,4 +1,8 @@
-use crate::{llamacpp::generate::get_embeddings, qdrant::client::VectorDB};
+use crate::api::schema::DistanceKind;
+use crate::embeddings::{embedder::Embedder, options::EmbedKind};
+use crate::qdrant::metadata::{verify_metadata, CollectionMetadata};
+use crate::store::filter::SearchFilter;
+use crate::store::vector::{SearchQuery, VectorStore};
 use custom_logger as log;
 use std::{
     io::{self, Write},
@@ -9,44 +13,121 @@ use crate::chat::{client::ChatClient, model::CompletionRequest, model::Message};
 
 #[allow(unused)]
 pub struct ChatSession {
-    qclient: VectorDB,
+    store: Arc<dyn VectorStore>,
     client: Arc<dyn ChatClient>,
     model: String,
-    embedding_url: String,
+    embedder: Arc<Embedder>,
     category: String,
+    // settings the collection is expected to be indexed with
+    metadata: Option<CollectionMetadata>,
+    warn_on_mismatch: bool,
     messages: Vec<Message>,
     search_limit: u64,
     score_threshold: f32,
+    // euclid and manhattan scores are distances, closer chunks score lower
+    lower_is_closer: bool,
+    hnsw_ef: Option<u64>,
+    // applied to every search, changed with the /filter command
+    filter: SearchFilter,
 }
 
 impl ChatSession {
     pub fn new(
-        qclient: VectorDB,
+        store: Arc<dyn VectorStore>,
         client: Arc<dyn ChatClient>,
         model: String,
-        url: String,
+        embedder: Arc<Embedder>,
         category: String,
         search_limit: u64,
         score_threshold: f32,
     ) -> Self {
         Self {
-            qclient,
+            store,
             client,
             model,
-            embedding_url: url,
+            embedder,
             category,
+            metadata: None,
+            warn_on_mismatch: false,
             messages: Vec::new(),
             search_limit,
             score_threshold,
+            lower_is_closer: false,
+            hnsw_ef: None,
+            filter: SearchFilter::default(),
         }
     }
 
+    // verify the collection metadata against these settings at startup
+    pub fn with_metadata_check(mut self, metadata: CollectionMetadata, warn: bool) -> Self {
+        self.metadata = Some(metadata);
+        self.warn_on_mismatch = warn;
+        self
+    }
+
+    // hybrid searches always score with the fused rank (higher is closer)
+    pub fn with_distance(mut self, distance: &DistanceKind, hybrid: bool) -> Self {
+        self.lower_is_closer = distance.lower_is_closer() && !hybrid;
+        self
+    }
+
+    fn within_threshold(&self, score: f32) -> bool {
+        if self.lower_is_closer {
+            score < self.score_threshold
+        } else {
+            score > self.score_threshold
+        }
+    }
+
+    // search with this hnsw ef instead of the collection default
+    pub fn with_hnsw_ef(mut self, hnsw_ef: Option<u64>) -> Self {
+        self.hnsw_ef = hnsw_ef;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: SearchFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // "/filter" shows the filter, "/filter clear" removes it and
+    // "/filter <terms>" replaces it (i.e. /filter path:ops ext:sh tag:ocp)
+    fn filter_command(&mut self, args: &str) {
+        match args {
+            "" => {}
+            "clear" => self.filter = SearchFilter::default(),
+            _ => match SearchFilter::parse(args) {
+                Ok(filter) => self.filter = filter,
+                Err(err) => {
+                    log::error!("filter {}", err);
+                    return;
+                }
+            },
+        }
+        log::info!("search filter : {}", self.filter);
+    }
+
     pub fn add_system_prompt(&mut self, prompt: impl ToString) {
         self.messages.push(Message::system(prompt));
     }
 
     pub async fn chat(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // a collection built with another embedding model can't be searched
+        let dimension = self.embedder.dimension().await?;
+        self.store
+            .check_vector_size(self.category.clone(), dimension)
+            .await?;
+        if let Some(metadata) = self.metadata.as_mut() {
+            metadata.dimension = dimension;
+            let stored = self.store.read_metadata(self.category.clone()).await?;
+            verify_metadata(&self.category, stored, metadata, self.warn_on_mismatch)?;
+        }
+
         log::info!("welcome!! input your question at the prompt. Use 'exit' to quit");
+        log::info!("restrict the search with '/filter path:<dir> ext:<ext> tag:<tag> after:<date> before:<date>'");
+        if !self.filter.is_empty() {
+            log::info!("search filter : {}", self.filter);
+        }
 
         let mut prompt = "A chat between a curious human and an artificial intelligence assistant. The assistant gives helpful, detailed, and polite answers to the human's questions.".to_owned();
         //### Human: Hello, Assistant.
@@ -70,11 +151,33 @@ impl ChatSession {
                 break;
             }
 
-            let res = get_embeddings(self.embedding_url.clone(), input.clone()).await;
-            let qdrant_res = self
-                .qclient
-                .search(self.category.clone(), res.unwrap(), self.search_limit)
-                .await;
+            if let Some(args) = input.strip_prefix("/filter") {
+                self.filter_command(args.trim());
+                continue;
+            }
+
+            // a failed search is reported and the session goes on
+            let embedding = match self.embedder.embed(EmbedKind::Query, input.clone()).await {
+                Ok(embedding) => embedding,
+                Err(err) => {
+                    log::error!("embedding {}", err);
+                    continue;
+                }
+            };
+            let query = SearchQuery {
+                embedding,
+                text: input.clone(),
+                limit: self.search_limit,
+                hnsw_ef: self.hnsw_ef,
+                filter: self.filter.clone(),
+            };
+            let search_res = match self.store.search(self.category.clone(), query).await {
+                Ok(search_res) => search_res,
+                Err(err) => {
+                    log::error!("search {}", err);
+                    continue;
+                }
+            };
 
             let mut extra_prompt =
                 "\nAnswer the question based only on the following context:\n\n".to_string();
@@ -82,16 +185,22 @@ impl ChatSession {
             let question = "Summarize the answer based on the above context: ".to_string();
             let mut source = "".to_string();
             let mut found = false;
-            for result in qdrant_res.as_ref().unwrap().iter() {
-                if result.score > self.score_threshold {
-                    let map = result.payload.clone();
-                    log::info!("score {}", result.score);
-                    let content = map["contents"].as_str().unwrap();
-                    extra_prompt.push_str(&content.clone());
-                    extra_prompt.push_str("\n --- \n");
-                    source.push_str(&format!("{} ", map["id"].as_str().unwrap()));
-                    found = true;
+            for result in search_res.iter() {
+                if !self.within_threshold(result.score) {
+                    continue;
                 }
+                let map = &result.payload;
+                let content = map.get("contents").and_then(|v| v.as_str());
+                let id = map.get("id").and_then(|v| v.as_str());
+                let (Some(content), Some(id)) = (content, id) else {
+                    log::warn!("skipping search hit {} without contents or id", result.id);
+                    continue;
+                };
+                log::info!("score {}", result.score);
+                extra_prompt.push_str(content);
+                extra_prompt.push_str("\n --- \n");
+                source.push_str(&format!("{} ", id));
+                found = true;
             }
 
             if found {
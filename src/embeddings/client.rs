@@ -0,0 +1,200 @@
+use crate::api::schema::Spec;
+use crate::error::handler::EmbeddingClientError;
+use custom_logger as log;
+use reqwest::header::ACCEPT;
+use reqwest::Client as HttpClient;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, sleep_until, Instant};
+
+// defaults when not set in the config
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 60_000;
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 250;
+// upper bound for a single backoff wait
+const MAX_RETRY_BACKOFF_MS: u64 = 10_000;
+// response body characters kept in status errors
+const ERROR_BODY_LENGTH: usize = 200;
+
+// Spaces requests at least interval apart, shared by all embedding workers
+struct RateLimiter {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    async fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().await;
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        sleep_until(slot).await;
+    }
+}
+
+// Long lived http client shared by the embedding providers, with connect and
+// request timeouts, retries with exponential backoff and an optional rate limit
+pub struct EmbeddingClient {
+    client: HttpClient,
+    retries: u32,
+    backoff: Duration,
+    limiter: Option<RateLimiter>,
+}
+
+impl EmbeddingClient {
+    pub fn from_spec(spec: &Spec) -> Result<Self, EmbeddingClientError> {
+        let connect_timeout = spec
+            .embedding_connect_timeout_ms
+            .unwrap_or(DEFAULT_CONNECT_TIMEOUT_MS);
+        let request_timeout = spec
+            .embedding_timeout_ms
+            .unwrap_or(DEFAULT_REQUEST_TIMEOUT_MS);
+        let mut builder = HttpClient::builder()
+            .connect_timeout(Duration::from_millis(connect_timeout))
+            .timeout(Duration::from_millis(request_timeout));
+        if !spec.proxy {
+            builder = builder.no_proxy();
+        }
+        let client = builder
+            .build()
+            .map_err(|err| EmbeddingClientError::Build(err.to_string()))?;
+        // requests per second, unset or 0 means no limit
+        let limiter = spec
+            .embedding_rate_limit
+            .filter(|rate| *rate > 0.0)
+            .map(|rate| RateLimiter {
+                interval: Duration::from_secs_f64(1.0 / rate),
+                next: Mutex::new(Instant::now()),
+            });
+        Ok(Self {
+            client,
+            retries: spec.embedding_retries.unwrap_or(DEFAULT_RETRIES),
+            backoff: Duration::from_millis(
+                spec.embedding_retry_backoff_ms
+                    .unwrap_or(DEFAULT_RETRY_BACKOFF_MS),
+            ),
+            limiter,
+        })
+    }
+
+    // post a json body and decode the json response, errors that are worth
+    // retrying (see EmbeddingClientError::is_retryable) are retried with
+    // exponential backoff
+    pub async fn post_json<B, R>(
+        &self,
+        url: &str,
+        body: &B,
+        bearer: Option<&str>,
+    ) -> Result<R, EmbeddingClientError>
+    where
+        B: Serialize + Sync + ?Sized,
+        R: DeserializeOwned,
+    {
+        let mut attempt = 0;
+        loop {
+            if let Some(limiter) = self.limiter.as_ref() {
+                limiter.wait().await;
+            }
+            match self.send(url, body, bearer).await {
+                Err(err) if err.is_retryable() && attempt < self.retries => {
+                    let wait = backoff(self.backoff, attempt);
+                    attempt += 1;
+                    log::warn!(
+                        "{}, retrying in {:?} ({}/{})",
+                        err,
+                        wait,
+                        attempt,
+                        self.retries
+                    );
+                    sleep(wait).await;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    async fn send<B, R>(
+        &self,
+        url: &str,
+        body: &B,
+        bearer: Option<&str>,
+    ) -> Result<R, EmbeddingClientError>
+    where
+        B: Serialize + Sync + ?Sized,
+        R: DeserializeOwned,
+    {
+        let mut request = self
+            .client
+            .post(url)
+            .header(ACCEPT, "application/json")
+            .json(body);
+        if let Some(token) = bearer {
+            request = request.bearer_auth(token);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|err| request_error(url, err))?;
+        let status = response.status();
+        let bytes = response
+            .bytes()
+            .await
+            .map_err(|err| request_error(url, err))?;
+        log::trace!("embedding response {} {} bytes", status, bytes.len());
+        if !status.is_success() {
+            return Err(EmbeddingClientError::Status {
+                url: url.to_string(),
+                status: status.as_u16(),
+                body: String::from_utf8_lossy(&bytes)
+                    .chars()
+                    .take(ERROR_BODY_LENGTH)
+                    .collect(),
+            });
+        }
+        serde_json::from_slice(&bytes).map_err(|err| EmbeddingClientError::Decode {
+            url: url.to_string(),
+            details: err.to_string(),
+        })
+    }
+}
+
+fn request_error(url: &str, err: reqwest::Error) -> EmbeddingClientError {
+    if err.is_timeout() {
+        EmbeddingClientError::Timeout {
+            url: url.to_string(),
+        }
+    } else {
+        EmbeddingClientError::Request {
+            url: url.to_string(),
+            details: err.to_string(),
+        }
+    }
+}
+
+// wait before retry attempt (0 based), doubles every attempt up to the max
+fn backoff(base: Duration, attempt: u32) -> Duration {
+    base.saturating_mul(2u32.saturating_pow(attempt))
+        .min(Duration::from_millis(MAX_RETRY_BACKOFF_MS))
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn backoff_pass() {
+        let base = Duration::from_millis(250);
+        assert_eq!(backoff(base, 0), Duration::from_millis(250));
+        assert_eq!(backoff(base, 2), Duration::from_millis(1000));
+        assert_eq!(
+            backoff(base, 20),
+            Duration::from_millis(MAX_RETRY_BACKOFF_MS)
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use crate::cache::store::EmbeddingCache;
+use crate::embeddings::options::{EmbedKind, EmbeddingOptions};
+use crate::embeddings::provider::EmbeddingProvider;
+use crate::error::handler::EmbeddingsError;
+use custom_logger as log;
+use tokio::sync::OnceCell;
+
+// text embedded to find the vector size of the model
+const DIMENSION_PROBE: &str = "dimension probe";
+// texts sent per embedding request when not set in the config
+pub const DEFAULT_EMBEDDING_BATCH_SIZE: usize = 16;
+
+// Embeds text with the configured embedding provider, when a cache is set the
+// request is skipped for (model, text) pairs that were embedded before. The
+// cache holds the raw vectors, the options are applied after the lookup
+pub struct Embedder {
+    provider: Box<dyn EmbeddingProvider>,
+    model: String,
+    cache: Option<EmbeddingCache>,
+    batch_size: usize,
+    options: EmbeddingOptions,
+    dimension: OnceCell<u64>,
+}
+
+impl Embedder {
+    pub fn new(
+        provider: Box<dyn EmbeddingProvider>,
+        model: String,
+        cache: Option<EmbeddingCache>,
+        batch_size: usize,
+        options: EmbeddingOptions,
+    ) -> Self {
+        Self {
+            provider,
+            model,
+            cache,
+            batch_size: batch_size.max(1),
+            options,
+            dimension: OnceCell::new(),
+        }
+    }
+
+    // vector size produced by the model, probed once with a short text
+    pub async fn dimension(&self) -> Result<u64, Box<dyn std::error::Error>> {
+        let dimension = self
+            .dimension
+            .get_or_try_init(|| async {
+                let embedding = self
+                    .embed(EmbedKind::Document, DIMENSION_PROBE.to_string())
+                    .await?;
+                log::info!(
+                    "embedding model {} dimension {}",
+                    self.model,
+                    embedding.len()
+                );
+                Ok::<u64, Box<dyn std::error::Error>>(embedding.len() as u64)
+            })
+            .await?;
+        Ok(*dimension)
+    }
+
+    // maximum number of texts sent in one request
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    pub async fn embed(
+        &self,
+        kind: EmbedKind,
+        content: String,
+    ) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let content = self.options.prefix(kind, &content);
+        if let Some(embedding) = self.cached(&content) {
+            return Ok(self.options.process(embedding));
+        }
+        let embedding = self.provider.embed(content.clone()).await?;
+        self.store(&content, &embedding);
+        Ok(self.options.process(embedding))
+    }
+
+    // embed several texts, cached texts are skipped and the rest is sent in
+    // requests of at most batch_size texts, the result is in input order
+    pub async fn embed_batch(
+        &self,
+        kind: EmbedKind,
+        contents: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let contents: Vec<String> = contents
+            .iter()
+            .map(|content| self.options.prefix(kind, content))
+            .collect();
+        let mut result: Vec<Option<Vec<f32>>> = contents
+            .iter()
+            .map(|content| self.cached(content))
+            .collect();
+        let missing: Vec<usize> = (0..contents.len())
+            .filter(|index| result[*index].is_none())
+            .collect();
+        for indexes in missing.chunks(self.batch_size) {
+            let batch: Vec<String> = indexes.iter().map(|i| contents[*i].clone()).collect();
+            let embeddings = self.provider.embed_batch(batch).await?;
+            for (index, embedding) in indexes.iter().zip(embeddings) {
+                self.store(&contents[*index], &embedding);
+                result[*index] = Some(embedding);
+            }
+        }
+        result
+            .into_iter()
+            .map(|embedding| {
+                embedding.map(|e| self.options.process(e)).ok_or_else(|| {
+                    Box::new(EmbeddingsError::new(&format!(
+                        "{} : missing embedding in batch",
+                        self.provider.name()
+                    ))) as Box<dyn std::error::Error>
+                })
+            })
+            .collect()
+    }
+
+    fn cached(&self, content: &str) -> Option<Vec<f32>> {
+        let cache = self.cache.as_ref()?;
+        match cache.get(&self.model, content) {
+            Ok(Some(embedding)) => {
+                log::trace!("embedding cache hit");
+                Some(embedding)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                log::warn!("embedding cache read {}", err);
+                None
+            }
+        }
+    }
+
+    fn store(&self, content: &str, embedding: &[f32]) {
+        if let Some(cache) = self.cache.as_ref() {
+            if let Err(err) = cache.put(&self.model, content, embedding) {
+                log::warn!("embedding cache write {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+    use async_trait::async_trait;
+    use std::sync::{Arc, Mutex};
+
+    // returns the text length as the embedding and records the batch sizes
+    struct LengthProvider {
+        batches: Arc<Mutex<Vec<usize>>>,
+    }
+
+    #[async_trait]
+    impl EmbeddingProvider for LengthProvider {
+        fn name(&self) -> &str {
+            "length"
+        }
+
+        async fn embed(&self, content: String) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+            Ok(vec![content.len() as f32])
+        }
+
+        async fn embed_batch(
+            &self,
+            contents: Vec<String>,
+        ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+            self.batches.lock().unwrap().push(contents.len());
+            Ok(contents.iter().map(|c| vec![c.len() as f32]).collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn embed_batch_pass() {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let provider = LengthProvider {
+            batches: batches.clone(),
+        };
+        let options = EmbeddingOptions {
+            document_prefix: "p ".to_string(),
+            ..Default::default()
+        };
+        let embedder = Embedder::new(Box::new(provider), "test".to_string(), None, 2, options);
+        let contents: Vec<String> = ["a", "bb", "ccc", "dddd", "eeeee"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let embeddings = embedder
+            .embed_batch(EmbedKind::Document, contents)
+            .await
+            .unwrap();
+        assert_eq!(
+            embeddings,
+            vec![vec![3.0], vec![4.0], vec![5.0], vec![6.0], vec![7.0]]
+        );
+        assert_eq!(*batches.lock().unwrap(), vec![2, 2, 1]);
+    }
+}
@@ -0,0 +1,458 @@
+use crate::embeddings::provider::EmbeddingProvider;
+use crate::error::handler::EmbeddingsError;
+use async_trait::async_trait;
+use candle_core::quantized::gguf_file::{Content, Value};
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config, DTYPE};
+use custom_logger as log;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+// the vocabulary map shared by the tokenizers models
+use tokenizers::models::bpe::Vocab;
+use tokenizers::models::wordpiece::WordPiece;
+use tokenizers::normalizers::bert::BertNormalizer;
+use tokenizers::pre_tokenizers::bert::BertPreTokenizer;
+use tokenizers::processors::bert::BertProcessing;
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+// files expected in embeddingModelPath, the huggingface sentence-transformers
+// layout (i.e. sentence-transformers/all-MiniLM-L6-v2)
+const CONFIG_FILE: &str = "config.json";
+const TOKENIZER_FILE: &str = "tokenizer.json";
+const WEIGHTS_FILE: &str = "model.safetensors";
+// or a single llama.cpp file (i.e. all-MiniLM-L6-v2-Q8_0.gguf), the same
+// model the llama.cpp embedding server loads
+const GGUF_EXTENSION: &str = "gguf";
+// llama.cpp marks the start of a word, other wordpiece tokens continue one
+const PHANTOM_SPACE: char = '\u{2581}';
+
+struct LocalModel {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl LocalModel {
+    // mean pooling over the real (not padding) tokens followed by L2
+    // normalization, as done by sentence-transformers and the llama.cpp server
+    fn embed(&self, contents: Vec<String>) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(contents, true)
+            .map_err(|err| EmbeddingsError::new(&format!("tokenize {}", err)))?;
+        let mut ids = Vec::new();
+        let mut masks = Vec::new();
+        for encoding in encodings.iter() {
+            ids.push(Tensor::new(encoding.get_ids(), &self.device)?);
+            masks.push(Tensor::new(encoding.get_attention_mask(), &self.device)?);
+        }
+        let input_ids = Tensor::stack(&ids, 0)?;
+        let attention_mask = Tensor::stack(&masks, 0)?;
+        let token_type_ids = input_ids.zeros_like()?;
+        let hidden = self
+            .model
+            .forward(&input_ids, &token_type_ids, Some(&attention_mask))?;
+
+        let mask = attention_mask.to_dtype(DTYPE)?.unsqueeze(2)?;
+        let summed = hidden.broadcast_mul(&mask)?.sum(1)?;
+        let pooled = summed.broadcast_div(&mask.sum(1)?)?;
+        let norm = pooled.sqr()?.sum_keepdim(1)?.sqrt()?;
+        Ok(pooled.broadcast_div(&norm)?.to_vec2::<f32>()?)
+    }
+}
+
+// Runs a BERT sentence-embedding model (All-MiniLM and friends) in process on
+// the cpu, no embedding server needed
+pub struct LocalProvider {
+    inner: Arc<LocalModel>,
+}
+
+impl LocalProvider {
+    // path is a huggingface folder or a .gguf file, quantized gguf weights are
+    // dequantized to f32 when loaded
+    pub fn load(path: &str, max_tokens: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = Path::new(path);
+        let (model, config, mut tokenizer) =
+            if path.extension().is_some_and(|ext| ext == GGUF_EXTENSION) {
+                load_gguf(path)?
+            } else {
+                load_safetensors(path)?
+            };
+        tokenizer
+            .with_padding(Some(PaddingParams::default()))
+            .with_truncation(Some(TruncationParams {
+                max_length: max_tokens,
+                ..Default::default()
+            }))
+            .map_err(|err| EmbeddingsError::new(&format!("tokenizer {}", err)))?;
+        log::info!(
+            "loaded local embedding model {:?} ({} layers, hidden size {})",
+            path,
+            config.num_hidden_layers,
+            config.hidden_size
+        );
+        Ok(Self {
+            inner: Arc::new(LocalModel {
+                model,
+                tokenizer,
+                device: Device::Cpu,
+            }),
+        })
+    }
+}
+
+fn load_safetensors(
+    dir: &Path,
+) -> Result<(BertModel, Config, Tokenizer), Box<dyn std::error::Error>> {
+    let config: Config = serde_json::from_str(&fs::read_to_string(dir.join(CONFIG_FILE))?)?;
+    let tokenizer = Tokenizer::from_file(dir.join(TOKENIZER_FILE))
+        .map_err(|err| EmbeddingsError::new(&format!("tokenizer {:?} {}", dir, err)))?;
+    // the weights file is mapped read only and never modified while loaded
+    let vb = unsafe {
+        VarBuilder::from_mmaped_safetensors(&[dir.join(WEIGHTS_FILE)], DTYPE, &Device::Cpu)?
+    };
+    let model = BertModel::load(vb, &config)?;
+    Ok((model, config, tokenizer))
+}
+
+// A BERT model converted with llama.cpp convert_hf_to_gguf.py, the config
+// and the wordpiece vocabulary are read from the gguf metadata
+fn load_gguf(path: &Path) -> Result<(BertModel, Config, Tokenizer), Box<dyn std::error::Error>> {
+    let mut file = fs::File::open(path)?;
+    let content = Content::read(&mut file)?;
+    let metadata = &content.metadata;
+    let architecture = gguf_value(metadata, "general.architecture")?.to_string()?;
+    if architecture != "bert" {
+        return Err(Box::new(EmbeddingsError::new(&format!(
+            "gguf model {:?} is a {} model, only bert models are supported",
+            path, architecture
+        ))));
+    }
+
+    let device = Device::Cpu;
+    let mut tensors = HashMap::new();
+    for name in content.tensor_infos.keys() {
+        let Some(bert_name) = bert_tensor_name(name) else {
+            log::debug!("skipping gguf tensor {}", name);
+            continue;
+        };
+        let tensor = content.tensor(&mut file, name, &device)?;
+        tensors.insert(bert_name, tensor.dequantize(&device)?);
+    }
+    let type_vocab_size = match tensors.get("embeddings.token_type_embeddings.weight") {
+        Some(tensor) => tensor.dim(0)?,
+        None => 2,
+    };
+
+    let count = |key: &str| -> Result<usize, Box<dyn std::error::Error>> {
+        Ok(gguf_value(metadata, &format!("bert.{}", key))?.to_u64()? as usize)
+    };
+    let tokenizer = gguf_tokenizer(metadata)?;
+    let config = Config {
+        vocab_size: tokenizer.get_vocab_size(true),
+        hidden_size: count("embedding_length")?,
+        num_hidden_layers: count("block_count")?,
+        num_attention_heads: count("attention.head_count")?,
+        intermediate_size: count("feed_forward_length")?,
+        max_position_embeddings: count("context_length")?,
+        type_vocab_size,
+        layer_norm_eps: gguf_value(metadata, "bert.attention.layer_norm_epsilon")?.to_f32()? as f64,
+        ..Default::default()
+    };
+    let vb = VarBuilder::from_tensors(tensors, DTYPE, &device);
+    let model = BertModel::load(vb, &config)?;
+    Ok((model, config, tokenizer))
+}
+
+fn gguf_value<'a>(
+    metadata: &'a HashMap<String, Value>,
+    key: &str,
+) -> Result<&'a Value, Box<dyn std::error::Error>> {
+    metadata.get(key).ok_or_else(|| {
+        Box::new(EmbeddingsError::new(&format!(
+            "gguf metadata {} not found",
+            key
+        ))) as Box<dyn std::error::Error>
+    })
+}
+
+// llama.cpp tensor name to the huggingface name BertModel loads, none for
+// tensors the embeddings don't use (i.e. the pooler)
+fn bert_tensor_name(name: &str) -> Option<String> {
+    let (base, kind) = name.rsplit_once('.')?;
+    let bert_name = match base {
+        "token_embd" => "embeddings.word_embeddings".to_string(),
+        "token_types" => "embeddings.token_type_embeddings".to_string(),
+        "position_embd" => "embeddings.position_embeddings".to_string(),
+        "token_embd_norm" => "embeddings.LayerNorm".to_string(),
+        _ => {
+            let (layer, part) = base.strip_prefix("blk.")?.split_once('.')?;
+            let part = match part {
+                "attn_q" => "attention.self.query",
+                "attn_k" => "attention.self.key",
+                "attn_v" => "attention.self.value",
+                "attn_output" => "attention.output.dense",
+                "attn_output_norm" => "attention.output.LayerNorm",
+                "ffn_up" => "intermediate.dense",
+                "ffn_down" => "output.dense",
+                "layer_output_norm" => "output.LayerNorm",
+                _ => return None,
+            };
+            format!("encoder.layer.{}.{}", layer, part)
+        }
+    };
+    Some(format!("{}.{}", bert_name, kind))
+}
+
+// Rebuild the BERT wordpiece tokenizer, llama.cpp stores word starts with a
+// leading phantom space and continuations without the "##" prefix
+fn gguf_tokenizer(
+    metadata: &HashMap<String, Value>,
+) -> Result<Tokenizer, Box<dyn std::error::Error>> {
+    let mut vocab = Vocab::new();
+    for (id, token) in gguf_value(metadata, "tokenizer.ggml.tokens")?
+        .to_vec()?
+        .iter()
+        .enumerate()
+    {
+        let token = token.to_string()?;
+        let token = match token.strip_prefix(PHANTOM_SPACE) {
+            Some(word) => word.to_string(),
+            // special tokens ([CLS], [SEP], [unused0] ...) are kept as is
+            None if token.starts_with('[') && token.ends_with(']') => token.clone(),
+            None => format!("##{}", token),
+        };
+        vocab.insert(token, id as u32);
+    }
+    let id_token = |key: &str| -> Result<(String, u32), Box<dyn std::error::Error>> {
+        let id = gguf_value(metadata, &format!("tokenizer.ggml.{}", key))?.to_u32()?;
+        let token = vocab
+            .iter()
+            .find(|(_, v)| **v == id)
+            .map(|(token, _)| token.clone())
+            .unwrap_or_default();
+        Ok((token, id))
+    };
+    let cls = id_token("cls_token_id")?;
+    // sic, the key written by llama.cpp
+    let sep = id_token("seperator_token_id")?;
+    let unk = id_token("unknown_token_id")
+        .map(|(token, _)| token)
+        .unwrap_or("[UNK]".to_string());
+    // uncased models only have lower case tokens
+    let lowercase = !vocab
+        .keys()
+        .filter(|token| !token.starts_with('['))
+        .any(|token| token.chars().any(char::is_uppercase));
+
+    let wordpiece = WordPiece::builder()
+        .vocab(vocab)
+        .unk_token(unk)
+        .build()
+        .map_err(|err| EmbeddingsError::new(&format!("gguf tokenizer {}", err)))?;
+    let mut tokenizer = Tokenizer::new(wordpiece);
+    tokenizer
+        .with_normalizer(Some(BertNormalizer::new(true, true, None, lowercase)))
+        .with_pre_tokenizer(Some(BertPreTokenizer))
+        .with_post_processor(Some(BertProcessing::new(sep, cls)));
+    Ok(tokenizer)
+}
+
+#[async_trait]
+impl EmbeddingProvider for LocalProvider {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    async fn embed(&self, content: String) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let mut embeddings = self.embed_batch(vec![content]).await?;
+        Ok(embeddings.remove(0))
+    }
+
+    // inference is cpu bound, it runs on the blocking pool so the embedding
+    // workers don't stall the runtime
+    async fn embed_batch(
+        &self,
+        contents: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let inner = self.inner.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            inner.embed(contents).map_err(|err| err.to_string())
+        })
+        .await?;
+        res.map_err(|err| Box::new(EmbeddingsError::new(&err)) as Box<dyn std::error::Error>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+    use candle_nn::VarMap;
+    use tokenizers::models::wordlevel::WordLevel;
+    use tokenizers::pre_tokenizers::whitespace::Whitespace;
+
+    // tiny randomly initialized bert with a word level tokenizer
+    fn tiny_model() -> LocalModel {
+        let config = Config {
+            vocab_size: 8,
+            hidden_size: 16,
+            num_hidden_layers: 2,
+            num_attention_heads: 2,
+            intermediate_size: 32,
+            max_position_embeddings: 32,
+            ..Default::default()
+        };
+        let device = Device::Cpu;
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DTYPE, &device);
+        let model = BertModel::load(vb, &config).unwrap();
+        let vocab = ["[PAD]", "[UNK]", "deploy", "the", "script"]
+            .iter()
+            .enumerate()
+            .map(|(id, token)| (token.to_string(), id as u32));
+        let wordlevel = WordLevel::builder()
+            .vocab(vocab.collect())
+            .unk_token("[UNK]".to_string())
+            .build()
+            .unwrap();
+        let mut tokenizer = Tokenizer::new(wordlevel);
+        tokenizer.with_pre_tokenizer(Some(Whitespace {}));
+        tokenizer.with_padding(Some(PaddingParams::default()));
+        LocalModel {
+            model,
+            tokenizer,
+            device,
+        }
+    }
+
+    // tiny randomly initialized bert in the layout written by llama.cpp
+    fn write_tiny_gguf(path: &Path) {
+        use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+        let device = Device::Cpu;
+        let tokens = [
+            "[PAD]",
+            "[UNK]",
+            "[CLS]",
+            "[SEP]",
+            "▁deploy",
+            "▁the",
+            "▁script",
+            "s",
+        ];
+        let mut shapes: Vec<(String, Vec<usize>)> = vec![
+            ("token_embd.weight".to_string(), vec![8, 16]),
+            ("token_types.weight".to_string(), vec![2, 16]),
+            ("position_embd.weight".to_string(), vec![32, 16]),
+            ("token_embd_norm.weight".to_string(), vec![16]),
+            ("token_embd_norm.bias".to_string(), vec![16]),
+        ];
+        for layer in 0..2 {
+            for (name, shape) in [
+                ("attn_q", vec![16, 16]),
+                ("attn_k", vec![16, 16]),
+                ("attn_v", vec![16, 16]),
+                ("attn_output", vec![16, 16]),
+                ("attn_output_norm", vec![16]),
+                ("ffn_up", vec![32, 16]),
+                ("ffn_down", vec![16, 32]),
+                ("layer_output_norm", vec![16]),
+            ] {
+                let bias = vec![shape[0]];
+                shapes.push((format!("blk.{}.{}.weight", layer, name), shape));
+                shapes.push((format!("blk.{}.{}.bias", layer, name), bias));
+            }
+        }
+        let tensors: Vec<(String, QTensor)> = shapes
+            .into_iter()
+            .map(|(name, shape)| {
+                let tensor = Tensor::randn(0f32, 0.5, shape, &device).unwrap();
+                (name, QTensor::quantize(&tensor, GgmlDType::F32).unwrap())
+            })
+            .collect();
+        let metadata = [
+            ("general.architecture", Value::String("bert".to_string())),
+            ("bert.embedding_length", Value::U32(16)),
+            ("bert.block_count", Value::U32(2)),
+            ("bert.attention.head_count", Value::U32(2)),
+            ("bert.feed_forward_length", Value::U32(32)),
+            ("bert.context_length", Value::U32(32)),
+            ("bert.attention.layer_norm_epsilon", Value::F32(1e-12)),
+            (
+                "tokenizer.ggml.tokens",
+                Value::Array(
+                    tokens
+                        .iter()
+                        .map(|token| Value::String(token.to_string()))
+                        .collect(),
+                ),
+            ),
+            ("tokenizer.ggml.cls_token_id", Value::U32(2)),
+            ("tokenizer.ggml.seperator_token_id", Value::U32(3)),
+            ("tokenizer.ggml.unknown_token_id", Value::U32(1)),
+        ];
+        let mut file = fs::File::create(path).unwrap();
+        gguf_file::write(
+            &mut file,
+            &metadata
+                .iter()
+                .map(|(key, value)| (*key, value))
+                .collect::<Vec<_>>(),
+            &tensors
+                .iter()
+                .map(|(name, tensor)| (name.as_str(), tensor))
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_gguf_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tiny-bert-f32.gguf");
+        write_tiny_gguf(&path);
+        let provider = LocalProvider::load(path.to_str().unwrap(), 16).unwrap();
+
+        let encoding = provider
+            .inner
+            .tokenizer
+            .encode("Deploy the scripts", true)
+            .unwrap();
+        assert_eq!(encoding.get_ids(), &[2, 4, 5, 6, 7, 3]);
+        assert_eq!(
+            bert_tensor_name("blk.1.ffn_up.bias").unwrap(),
+            "encoder.layer.1.intermediate.dense.bias"
+        );
+        assert_eq!(bert_tensor_name("cls.output.weight"), None);
+
+        let embedding = provider
+            .embed("deploy the script".to_string())
+            .await
+            .unwrap();
+        assert_eq!(embedding.len(), 16);
+        let norm: f32 = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+        assert!((norm - 1.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn local_embed_pass() {
+        let model = tiny_model();
+        let batch = model
+            .embed(vec!["deploy the script".to_string(), "deploy".to_string()])
+            .unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].len(), 16);
+        for embedding in batch.iter() {
+            let norm: f32 = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+            assert!((norm - 1.0).abs() < 1e-4);
+        }
+        // padding must not change the embedding of the shorter input
+        let single = model.embed(vec!["deploy".to_string()]).unwrap();
+        for (a, b) in single[0].iter().zip(batch[1].iter()) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,6 @@
+pub mod client;
+pub mod embedder;
+#[cfg(feature = "local-embeddings")]
+pub mod local;
+pub mod options;
+pub mod provider;
@@ -0,0 +1,121 @@
+use crate::api::schema::Spec;
+
+// Prefixes expected by well known model families, matched on the lower case
+// embeddingModel name as (name part, query prefix, document prefix), the
+// first match wins
+const MODEL_PREFIXES: &[(&str, &str, &str)] = &[
+    ("e5-", "query: ", "passage: "),
+    // bge-m3 is trained without an instruction
+    ("bge-m3", "", ""),
+    (
+        "bge-",
+        "Represent this sentence for searching relevant passages: ",
+        "",
+    ),
+    ("nomic-embed", "search_query: ", "search_document: "),
+];
+
+// Whether the text is a question from the chat or a chunk being indexed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmbedKind {
+    Query,
+    Document,
+}
+
+// How text is prepared before embedding and how the vectors are processed
+// after, shared by the indexer and the chat so both sides of a search match
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EmbeddingOptions {
+    pub query_prefix: String,
+    pub document_prefix: String,
+    // scale every vector to unit length
+    pub normalize: bool,
+    // keep only the first n dimensions (matryoshka models)
+    pub dimensions: Option<usize>,
+}
+
+// (query prefix, document prefix) of the model family, none for unknown models
+pub fn model_prefixes(model: &str) -> (&'static str, &'static str) {
+    let model = model.to_lowercase();
+    MODEL_PREFIXES
+        .iter()
+        .find(|(name, _, _)| model.contains(name))
+        .map_or(("", ""), |(_, query, document)| (*query, *document))
+}
+
+impl EmbeddingOptions {
+    // queryPrefix and documentPrefix default to no prefix, with autoPrefixes
+    // they default to the prefixes of the model family. Prefixes change the
+    // vectors so picking them is never implicit
+    pub fn from_spec(spec: &Spec) -> Self {
+        let (query, document) = if spec.auto_prefixes.unwrap_or(false) {
+            model_prefixes(&spec.embedding_model)
+        } else {
+            ("", "")
+        };
+        Self {
+            query_prefix: spec.query_prefix.clone().unwrap_or(query.to_string()),
+            document_prefix: spec.document_prefix.clone().unwrap_or(document.to_string()),
+            normalize: spec.normalize_embeddings.unwrap_or(false),
+            dimensions: spec.embedding_dimensions,
+        }
+    }
+
+    pub fn prefix(&self, kind: EmbedKind, content: &str) -> String {
+        match kind {
+            EmbedKind::Query => format!("{}{}", self.query_prefix, content),
+            EmbedKind::Document => format!("{}{}", self.document_prefix, content),
+        }
+    }
+
+    // truncate first so a truncated matryoshka vector is normalized again
+    pub fn process(&self, mut embedding: Vec<f32>) -> Vec<f32> {
+        if let Some(dimensions) = self.dimensions {
+            embedding.truncate(dimensions);
+        }
+        if self.normalize {
+            let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+            if norm > 0.0 {
+                embedding.iter_mut().for_each(|v| *v /= norm);
+            }
+        }
+        embedding
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn embedding_options_pass() {
+        let options = EmbeddingOptions {
+            query_prefix: "query: ".to_string(),
+            document_prefix: "passage: ".to_string(),
+            normalize: true,
+            dimensions: Some(2),
+        };
+        assert_eq!(options.prefix(EmbedKind::Query, "deploy"), "query: deploy");
+        assert_eq!(
+            options.prefix(EmbedKind::Document, "deploy"),
+            "passage: deploy"
+        );
+        assert_eq!(options.process(vec![3.0, 4.0, 12.0]), vec![0.6, 0.8]);
+        assert_eq!(
+            EmbeddingOptions::default().process(vec![3.0, 4.0]),
+            vec![3.0, 4.0]
+        );
+
+        assert_eq!(
+            model_prefixes("intfloat/multilingual-E5-large"),
+            ("query: ", "passage: ")
+        );
+        assert_eq!(model_prefixes("BAAI/bge-m3"), ("", ""));
+        assert_eq!(model_prefixes("BAAI/bge-small-en-v1.5").1, "");
+        assert!(model_prefixes("BAAI/bge-small-en-v1.5")
+            .0
+            .starts_with("Represent"));
+        assert_eq!(model_prefixes("all-MiniLM-L6-v2"), ("", ""));
+    }
+}
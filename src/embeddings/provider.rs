@@ -0,0 +1,249 @@
+use crate::api::schema::{EmbeddingProviderKind, Spec};
+use crate::embeddings::client::EmbeddingClient;
+#[cfg(feature = "local-embeddings")]
+use crate::embeddings::local::LocalProvider;
+use crate::error::handler::{EmbeddingClientError, EmbeddingsError};
+#[cfg(feature = "local-embeddings")]
+use crate::indexer::tokens::DEFAULT_MAX_TOKENS;
+use crate::llamacpp::generate::{get_embeddings, get_embeddings_batch};
+use async_trait::async_trait;
+use custom_logger as log;
+use serde_derive::{Deserialize, Serialize};
+
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    // name used in logs and error messages
+    fn name(&self) -> &str;
+    async fn embed(&self, content: String) -> Result<Vec<f32>, Box<dyn std::error::Error>>;
+
+    // embed several texts, the result is in input order
+    async fn embed_batch(
+        &self,
+        contents: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let mut result = Vec::new();
+        for content in contents.into_iter() {
+            result.push(self.embed(content).await?);
+        }
+        Ok(result)
+    }
+}
+
+// Create the provider selected with embeddingProvider in the config
+pub fn new_provider(spec: &Spec) -> Result<Box<dyn EmbeddingProvider>, Box<dyn std::error::Error>> {
+    let server = spec.embedding_server();
+    let client = EmbeddingClient::from_spec(spec)?;
+    let provider: Box<dyn EmbeddingProvider> =
+        match spec.embedding_provider.clone().unwrap_or_default() {
+            EmbeddingProviderKind::LlamaCpp => Box::new(LlamaCppProvider {
+                client,
+                url: format!("{}/embedding", server),
+            }),
+            EmbeddingProviderKind::OpenAI => Box::new(OpenAIProvider {
+                client,
+                url: format!("{}/v1/embeddings", server),
+                model: spec.embedding_model.clone(),
+                api_key: spec
+                    .embedding_api_key
+                    .clone()
+                    .unwrap_or(spec.openapi_key.clone()),
+            }),
+            EmbeddingProviderKind::Ollama => Box::new(OllamaProvider {
+                client,
+                url: format!("{}/api/embed", server),
+                model: spec.embedding_model.clone(),
+            }),
+            EmbeddingProviderKind::Local => local_provider(spec)?,
+        };
+    Ok(provider)
+}
+
+#[cfg(feature = "local-embeddings")]
+fn local_provider(spec: &Spec) -> Result<Box<dyn EmbeddingProvider>, Box<dyn std::error::Error>> {
+    let path = spec.embedding_model_path.clone().ok_or_else(|| {
+        EmbeddingsError::new("embeddingModelPath is required for the local embedding provider")
+    })?;
+    let max_tokens = spec.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS);
+    Ok(Box::new(LocalProvider::load(&path, max_tokens)?))
+}
+
+#[cfg(not(feature = "local-embeddings"))]
+fn local_provider(_spec: &Spec) -> Result<Box<dyn EmbeddingProvider>, Box<dyn std::error::Error>> {
+    Err(Box::new(EmbeddingsError::new(
+        "the local embedding provider needs a build with --features local-embeddings",
+    )))
+}
+
+// llama.cpp server native /embedding endpoint
+pub struct LlamaCppProvider {
+    client: EmbeddingClient,
+    url: String,
+}
+
+#[async_trait]
+impl EmbeddingProvider for LlamaCppProvider {
+    fn name(&self) -> &str {
+        "llamacpp"
+    }
+
+    async fn embed(&self, content: String) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        Ok(get_embeddings(&self.client, &self.url, content).await?)
+    }
+
+    async fn embed_batch(
+        &self,
+        contents: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        Ok(get_embeddings_batch(&self.client, &self.url, contents).await?)
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpenAIRequest {
+    pub model: String,
+    pub input: Vec<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpenAIEmbedding {
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpenAIResponse {
+    pub data: Vec<OpenAIEmbedding>,
+}
+
+impl OpenAIResponse {
+    // embeddings ordered by input index
+    pub fn embeddings(mut self) -> Vec<Vec<f32>> {
+        self.data.sort_by_key(|item| item.index);
+        self.data.into_iter().map(|item| item.embedding).collect()
+    }
+}
+
+// OpenAI compatible /v1/embeddings endpoint (also served by vLLM, llama.cpp
+// and most hosted APIs)
+pub struct OpenAIProvider {
+    client: EmbeddingClient,
+    url: String,
+    model: String,
+    api_key: String,
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAIProvider {
+    fn name(&self) -> &str {
+        "openai"
+    }
+
+    async fn embed(&self, content: String) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let embeddings = self.embed_batch(vec![content]).await?;
+        Ok(first_embedding(&self.url, embeddings)?)
+    }
+
+    async fn embed_batch(
+        &self,
+        contents: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let count = contents.len();
+        let request = OpenAIRequest {
+            model: self.model.clone(),
+            input: contents,
+        };
+        let response: OpenAIResponse = self
+            .client
+            .post_json(&self.url, &request, Some(&self.api_key))
+            .await?;
+        log::trace!("openai embeddings {:?}", response);
+        Ok(check_count(&self.url, count, response.embeddings())?)
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OllamaRequest {
+    pub model: String,
+    pub input: Vec<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OllamaResponse {
+    pub embeddings: Vec<Vec<f32>>,
+}
+
+// Ollama /api/embed endpoint
+pub struct OllamaProvider {
+    client: EmbeddingClient,
+    url: String,
+    model: String,
+}
+
+#[async_trait]
+impl EmbeddingProvider for OllamaProvider {
+    fn name(&self) -> &str {
+        "ollama"
+    }
+
+    async fn embed(&self, content: String) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let embeddings = self.embed_batch(vec![content]).await?;
+        Ok(first_embedding(&self.url, embeddings)?)
+    }
+
+    async fn embed_batch(
+        &self,
+        contents: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+        let count = contents.len();
+        let request = OllamaRequest {
+            model: self.model.clone(),
+            input: contents,
+        };
+        let response: OllamaResponse = self.client.post_json(&self.url, &request, None).await?;
+        log::trace!("ollama embeddings {:?}", response);
+        Ok(check_count(&self.url, count, response.embeddings)?)
+    }
+}
+
+fn first_embedding(url: &str, embeddings: Vec<Vec<f32>>) -> Result<Vec<f32>, EmbeddingClientError> {
+    match embeddings.into_iter().next() {
+        Some(embedding) if !embedding.is_empty() => Ok(embedding),
+        _ => Err(EmbeddingClientError::Response {
+            url: url.to_string(),
+            details: "no embeddings returned".to_string(),
+        }),
+    }
+}
+
+// every input must get a (non empty) embedding back
+fn check_count(
+    url: &str,
+    count: usize,
+    embeddings: Vec<Vec<f32>>,
+) -> Result<Vec<Vec<f32>>, EmbeddingClientError> {
+    if embeddings.len() != count || embeddings.iter().any(|e| e.is_empty()) {
+        return Err(EmbeddingClientError::Response {
+            url: url.to_string(),
+            details: format!("expected {} embeddings, got {}", count, embeddings.len()),
+        });
+    }
+    Ok(embeddings)
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn openai_response_pass() {
+        let body = r#"{"object":"list","data":[
+            {"object":"embedding","index":1,"embedding":[0.3,0.4]},
+            {"object":"embedding","index":0,"embedding":[0.1,0.2]}
+        ],"model":"all-minilm"}"#;
+        let response: OpenAIResponse = serde_json::from_str(body).unwrap();
+        assert_eq!(response.embeddings(), vec![vec![0.1, 0.2], vec![0.3, 0.4]]);
+        assert!(first_embedding("url", vec![]).is_err());
+        assert!(check_count("url", 2, vec![vec![0.1]]).is_err());
+    }
+}
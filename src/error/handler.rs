@@ -27,6 +27,77 @@ impl Error for EmbeddingsError {
     }
 }
 
+// Failure of a request to an embedding server
+#[derive(Debug)]
+pub enum EmbeddingClientError {
+    // the http client could not be created
+    Build(String),
+    // no response, the server could not be reached or the connection dropped
+    Request {
+        url: String,
+        details: String,
+    },
+    // no response within the connect or request timeout
+    Timeout {
+        url: String,
+    },
+    // non 2xx response, body holds the start of the response body
+    Status {
+        url: String,
+        status: u16,
+        body: String,
+    },
+    // the response body is not the expected json
+    Decode {
+        url: String,
+        details: String,
+    },
+    // the response is valid json but the embeddings are missing or empty
+    Response {
+        url: String,
+        details: String,
+    },
+}
+
+impl EmbeddingClientError {
+    // connection errors, timeouts, rate limiting (429) and server errors
+    // (5xx) are worth retrying, anything else will fail again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            EmbeddingClientError::Request { .. } | EmbeddingClientError::Timeout { .. } => true,
+            EmbeddingClientError::Status { status, .. } => *status >= 500 || *status == 429,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for EmbeddingClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmbeddingClientError::Build(details) => {
+                write!(f, "embedding client could not be created : {}", details)
+            }
+            EmbeddingClientError::Request { url, details } => {
+                write!(f, "embedding request to {} failed : {}", url, details)
+            }
+            EmbeddingClientError::Timeout { url } => {
+                write!(f, "embedding request to {} timed out", url)
+            }
+            EmbeddingClientError::Status { url, status, body } => {
+                write!(f, "embedding server {} returned {} : {}", url, status, body)
+            }
+            EmbeddingClientError::Decode { url, details } => {
+                write!(f, "invalid embedding response from {} : {}", url, details)
+            }
+            EmbeddingClientError::Response { url, details } => {
+                write!(f, "embedding response from {} : {}", url, details)
+            }
+        }
+    }
+}
+
+impl Error for EmbeddingClientError {}
+
 #[cfg(test)]
 #[allow(deprecated)]
 mod tests {
@@ -39,4 +110,29 @@ mod tests {
         assert_eq!(err.to_string(), "testing error 123456");
         assert_eq!(err.description(), "testing error 123456");
     }
+
+    #[test]
+    fn client_err_retryable_pass() {
+        let status = |status| EmbeddingClientError::Status {
+            url: "http://localhost:8085/embedding".to_string(),
+            status,
+            body: "".to_string(),
+        };
+        assert!(status(503).is_retryable());
+        assert!(status(429).is_retryable());
+        assert!(!status(400).is_retryable());
+        assert!(EmbeddingClientError::Timeout {
+            url: "http://localhost:8085/embedding".to_string()
+        }
+        .is_retryable());
+        assert!(!EmbeddingClientError::Decode {
+            url: "http://localhost:8085/embedding".to_string(),
+            details: "expected value".to_string()
+        }
+        .is_retryable());
+        assert_eq!(
+            status(500).to_string(),
+            "embedding server http://localhost:8085/embedding returned 500 : "
+        );
+    }
 }
@@ -0,0 +1,139 @@
+use crate::markdown::process::MarkdownFile;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Manifest of the chunks embedded and upserted so far for a category, used
+// to resume an interrupted run without starting from scratch
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Checkpoint {
+    #[serde(skip)]
+    path: PathBuf,
+    pub category: String,
+    // chunk path -> source file hash, for chunks embedded and upserted
+    pub done: BTreeMap<String, String>,
+    // chunk path -> error, for chunks that failed
+    pub failed: BTreeMap<String, String>,
+    // source file -> its failed chunk paths, the next incremental run embeds
+    // these files again
+    #[serde(default)]
+    pub failed_files: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Checkpoint {
+    // an empty checkpoint for the category
+    pub fn new(dir: &str, category: &str) -> Self {
+        Self {
+            path: Path::new(dir).join(format!("{}.json", category)),
+            category: category.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // the checkpoint left by a previous run, or an empty one
+    pub fn load(dir: &str, category: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut checkpoint = Self::new(dir, category);
+        if checkpoint.path.exists() {
+            let data = fs::read_to_string(&checkpoint.path)?;
+            let path = checkpoint.path.clone();
+            checkpoint = serde_json::from_str::<Checkpoint>(&data)?;
+            checkpoint.path = path;
+        }
+        Ok(checkpoint)
+    }
+
+    // true when the chunk was already upserted and its file hasn't changed since
+    pub fn is_done(&self, mkd: &MarkdownFile) -> bool {
+        self.done.get(&mkd.path) == Some(&mkd.hash)
+    }
+
+    // a file is no longer failed once none of its chunks is
+    pub fn mark_done(&mut self, path: String, file: String, hash: String) {
+        self.failed.remove(&path);
+        if let Some(paths) = self.failed_files.get_mut(&file) {
+            paths.remove(&path);
+            if paths.is_empty() {
+                self.failed_files.remove(&file);
+            }
+        }
+        self.done.insert(path, hash);
+    }
+
+    pub fn mark_failed(&mut self, path: String, file: String, error: String) {
+        self.failed.insert(path.clone(), error);
+        self.failed_files.entry(file).or_default().insert(path);
+    }
+
+    // forget the failures of files embedded again from scratch, their chunks
+    // may be numbered differently now
+    pub fn retry_files(&mut self, files: &[String]) {
+        for file in files.iter() {
+            for path in self.failed_files.remove(file).unwrap_or_default() {
+                self.failed.remove(&path);
+            }
+        }
+    }
+
+    // written to a temporary file first so an interrupted save can't corrupt it
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    pub fn remove(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn checkpoint_pass() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        let mkd = MarkdownFile {
+            path: "kb-docs/scripts/a.sh".to_string(),
+            hash: "1234".to_string(),
+            ..Default::default()
+        };
+
+        let mut checkpoint = Checkpoint::new(dir, "scripts");
+        checkpoint.mark_failed(mkd.path.clone(), mkd.file.clone(), "timeout".to_string());
+        checkpoint.mark_done(mkd.path.clone(), mkd.file.clone(), mkd.hash.clone());
+        assert!(checkpoint.failed_files.is_empty());
+        checkpoint.mark_failed(
+            "kb-docs/scripts/b.sh-0".to_string(),
+            "kb-docs/scripts/b.sh".to_string(),
+            "timeout".to_string(),
+        );
+        checkpoint.save().unwrap();
+
+        let loaded = Checkpoint::load(dir, "scripts").unwrap();
+        assert!(loaded.is_done(&mkd));
+        assert_eq!(loaded.failed.len(), 1);
+        assert!(loaded.failed_files.contains_key("kb-docs/scripts/b.sh"));
+        let mut retried = Checkpoint::load(dir, "scripts").unwrap();
+        retried.retry_files(&["kb-docs/scripts/b.sh".to_string()]);
+        assert!(retried.failed.is_empty() && retried.failed_files.is_empty());
+        let changed = MarkdownFile {
+            hash: "5678".to_string(),
+            ..mkd.clone()
+        };
+        assert!(!loaded.is_done(&changed));
+
+        loaded.remove().unwrap();
+        assert!(Checkpoint::load(dir, "scripts").unwrap().done.is_empty());
+    }
+}
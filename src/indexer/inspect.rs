@@ -0,0 +1,59 @@
+use crate::api::schema::{ChunkStrategy, Spec};
+use crate::indexer::process::load_chunks;
+use crate::indexer::tokens::{estimate_chunks, TokenLimits};
+use custom_logger as log;
+
+// characters of chunk text shown per chunk
+const PREVIEW_LENGTH: usize = 120;
+
+// Print every file that would be indexed, the chunks it produces, their
+// lengths and the text that would be embedded. Neither the store nor the
+// embedding server is used, chunk splits are estimated from the word counts
+pub fn inspect_categories(spec: &Spec) -> Result<(), Box<dyn std::error::Error>> {
+    for category in spec.categories().iter() {
+        let files = load_chunks(category)?;
+        let chunks = estimate_chunks(files, &TokenLimits::from_spec(category));
+        log::info!(
+            "inspecting {} chunks for {}",
+            chunks.len(),
+            category.category
+        );
+
+        println!(
+            "category {} ({})",
+            category.category,
+            category.docs_folder()
+        );
+        let mut current = String::new();
+        for mkd in chunks.iter() {
+            if mkd.file != current {
+                current = mkd.file.clone();
+                println!("  {} [{:?}]", mkd.file, mkd.strategy);
+            }
+            let text = mkd.embedding_text();
+            println!(
+                "    #{} {} : {} chars, {} words",
+                mkd.chunk,
+                mkd.path,
+                text.len(),
+                text.split_whitespace().count()
+            );
+            if let Some(breadcrumb) = mkd.breadcrumb.as_ref() {
+                println!("      breadcrumb : {}", breadcrumb);
+            }
+            for (name, value) in mkd.fields.iter() {
+                println!("      {} : {}", name, value);
+            }
+            if mkd.strategy == ChunkStrategy::Headers {
+                println!(
+                    "      header : {}",
+                    text.trim_end().replace('\n', "\n               ")
+                );
+            } else {
+                let preview: String = text.chars().take(PREVIEW_LENGTH).collect();
+                println!("      text : {}", preview.replace('\n', " "));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,8 @@
+pub mod checkpoint;
+pub mod inspect;
+pub mod pipeline;
+pub mod process;
+pub mod tokens;
+pub mod versions;
+pub mod watch;
+pub mod writer;
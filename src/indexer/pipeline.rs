@@ -0,0 +1,155 @@
+use crate::embeddings::embedder::Embedder;
+use crate::embeddings::options::EmbedKind;
+use crate::indexer::checkpoint::Checkpoint;
+use crate::indexer::writer::BatchWriter;
+use crate::markdown::process::MarkdownFile;
+use crate::store::vector::{to_point, VectorStore};
+use custom_logger as log;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+// queued embedding batches per worker on the loader channel
+const BATCHES_PER_WORKER: usize = 2;
+
+pub struct PipelineResult {
+    pub embedded: usize,
+    pub upserted: usize,
+    pub failed: Vec<String>,
+    pub checkpoint: Checkpoint,
+}
+
+struct Embedded {
+    mkd: MarkdownFile,
+    result: Result<Vec<f32>, String>,
+}
+
+// Embed a batch of chunks in one request, when the batch fails each chunk is
+// retried on its own so one bad chunk doesn't fail the whole batch
+async fn embed_chunks(embedder: &Embedder, batch: Vec<MarkdownFile>) -> Vec<Embedded> {
+    let contents: Vec<String> = batch.iter().map(|mkd| mkd.embedding_text()).collect();
+    let res = embedder
+        .embed_batch(EmbedKind::Document, contents)
+        .await
+        .map_err(|err| err.to_string());
+    match res {
+        Ok(embeddings) => batch
+            .into_iter()
+            .zip(embeddings)
+            .map(|(mkd, embedding)| Embedded {
+                mkd,
+                result: Ok(embedding),
+            })
+            .collect(),
+        Err(err) => {
+            log::warn!(
+                "batch of {} failed {}, retrying one by one",
+                batch.len(),
+                err
+            );
+            let mut result = Vec::new();
+            for mkd in batch.into_iter() {
+                let embedding = embedder
+                    .embed(EmbedKind::Document, mkd.embedding_text())
+                    .await
+                    .map_err(|err| err.to_string());
+                result.push(Embedded {
+                    mkd,
+                    result: embedding,
+                });
+            }
+            result
+        }
+    }
+}
+
+// Run the indexing pipeline : a loader feeds chunks to a pool of embedding
+// workers, the embedded chunks are then written to the store in batches. All
+// stages are connected with bounded channels so a slow embedding server
+// applies back pressure instead of queueing the whole kb in memory
+pub async fn run_pipeline(
+    store: &dyn VectorStore,
+    collection: String,
+    chunks: Vec<MarkdownFile>,
+    embedder: Arc<Embedder>,
+    workers: usize,
+    batch_size: usize,
+    checkpoint: Checkpoint,
+) -> PipelineResult {
+    let workers = workers.max(1);
+    let embed_batch = embedder.batch_size();
+    let (chunk_tx, chunk_rx) =
+        mpsc::channel::<MarkdownFile>(workers * embed_batch * BATCHES_PER_WORKER);
+    let (embedded_tx, mut embedded_rx) = mpsc::channel::<Embedded>(batch_size.max(1));
+
+    // loader
+    tokio::spawn(async move {
+        for mkd in chunks.into_iter() {
+            if chunk_tx.send(mkd).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // embedding workers
+    let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+    for id in 0..workers {
+        let chunk_rx = chunk_rx.clone();
+        let embedded_tx = embedded_tx.clone();
+        let embedder = embedder.clone();
+        tokio::spawn(async move {
+            loop {
+                // wait for one chunk then take whatever else is queued, up
+                // to the embedding batch size
+                let mut batch = Vec::new();
+                {
+                    let mut chunk_rx = chunk_rx.lock().await;
+                    let Some(mkd) = chunk_rx.recv().await else {
+                        break;
+                    };
+                    batch.push(mkd);
+                    while batch.len() < embed_batch {
+                        match chunk_rx.try_recv() {
+                            Ok(mkd) => batch.push(mkd),
+                            Err(_) => break,
+                        }
+                    }
+                }
+                log::debug!("worker {} embedding {} chunks", id, batch.len());
+                for item in embed_chunks(&embedder, batch).await.into_iter() {
+                    if embedded_tx.send(item).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+    }
+    // the writer stops once every worker has dropped its sender
+    drop(embedded_tx);
+
+    // batched store writer
+    let mut writer = BatchWriter::new(store, collection, batch_size, checkpoint);
+    let mut embedded = 0;
+    while let Some(item) = embedded_rx.recv().await {
+        let point = item
+            .result
+            .map(|embedding| to_point(embedding, &item.mkd, store.hybrid()));
+        match point {
+            Ok(point) => {
+                embedded += 1;
+                writer.push(&item.mkd, point).await;
+            }
+            Err(err) => {
+                log::error!("embedding {} failed {}", item.mkd.path, err);
+                writer.record_failure(&item.mkd, err);
+            }
+        }
+    }
+    writer.flush().await;
+
+    PipelineResult {
+        embedded,
+        upserted: writer.upserted,
+        failed: writer.failed,
+        checkpoint: writer.checkpoint,
+    }
+}
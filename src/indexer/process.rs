@@ -0,0 +1,736 @@
+use crate::api::schema::Spec;
+use crate::embeddings::embedder::Embedder;
+use crate::indexer::checkpoint::Checkpoint;
+use crate::indexer::pipeline::{run_pipeline, PipelineResult};
+use crate::indexer::tokens::{estimate_chunks, fit_chunks, TokenCounter, TokenLimits};
+use crate::indexer::versions::{
+    activate, active_collection, next_version, unfinished_version, DEFAULT_KEEP_VERSIONS,
+};
+use crate::markdown::process::*;
+use crate::qdrant::metadata::{verify_metadata, CollectionMetadata};
+use crate::store::vector::{IndexedFile, VectorStore};
+use custom_logger as log;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// number of points sent per store upsert when not set in the config
+const DEFAULT_UPSERT_BATCH_SIZE: usize = 64;
+// where checkpoints are written when not set in the config
+const DEFAULT_CHECKPOINT_DIR: &str = ".checkpoints";
+// concurrent embedding requests when not set in the config
+const DEFAULT_EMBEDDING_WORKERS: usize = 1;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct IndexPlan {
+    // new or modified source files, these get (re)embedded
+    pub changed: Vec<String>,
+    // source files that no longer exist on disk
+    pub deleted: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+impl IndexPlan {
+    // files with failed chunks in an earlier run are embedded again even when
+    // they look unchanged, some of their chunks are missing
+    pub fn retry(&mut self, failed: &BTreeMap<String, BTreeSet<String>>) {
+        let (retry, unchanged) = self
+            .unchanged
+            .drain(..)
+            .partition(|file| failed.contains_key(file));
+        self.unchanged = unchanged;
+        self.changed.extend::<Vec<String>>(retry);
+        self.changed.sort();
+    }
+}
+
+// Compare the loaded chunks against the file state stored in the collection
+pub fn plan_changes(files: &[MarkdownFile], indexed: &HashMap<String, IndexedFile>) -> IndexPlan {
+    let mut plan = IndexPlan::default();
+    let mut seen: HashMap<&str, &str> = HashMap::new();
+    for mkd in files.iter() {
+        seen.insert(&mkd.file, &mkd.hash);
+    }
+    // sorted for stable logging
+    let on_disk: BTreeSet<&str> = seen.keys().copied().collect();
+    for file in on_disk.into_iter() {
+        match indexed.get(file) {
+            Some(state) if state.hash == seen[file] => plan.unchanged.push(file.to_string()),
+            _ => plan.changed.push(file.to_string()),
+        }
+    }
+    let mut deleted: Vec<String> = indexed
+        .keys()
+        .filter(|file| !seen.contains_key(file.as_str()))
+        .cloned()
+        .collect();
+    deleted.sort();
+    plan.deleted = deleted;
+    plan
+}
+
+#[derive(Debug, Default)]
+pub struct IndexSummary {
+    pub category: String,
+    pub files: usize,
+    pub chunks: usize,
+    pub embedded: usize,
+    pub upserted: usize,
+    pub deleted: usize,
+    pub failed: Vec<String>,
+    pub elapsed: Duration,
+    // set when the category could not be indexed at all
+    pub error: Option<String>,
+}
+
+impl IndexSummary {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none() && self.failed.is_empty()
+    }
+}
+
+// Flags that change how a category is indexed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IndexMode {
+    // only re-embed new or changed files
+    pub incremental: bool,
+    // continue from the checkpoint left by an interrupted run
+    pub resume: bool,
+}
+
+// Load and chunk the files of a category, shared by indexing and inspect
+pub fn load_chunks(spec: &Spec) -> Result<Vec<MarkdownFile>, Box<dyn std::error::Error>> {
+    let options = ChunkOptions::from_spec(spec)?;
+    let selector = FileSelector::from_spec(spec)?;
+    load_files_from_dir(
+        spec.docs_folder().into(),
+        Path::new("."),
+        &selector,
+        &options,
+    )
+}
+
+// Chunks of the selected files below the changed paths (relative to folder,
+// the canonical docs folder) and the ids of the paths with nothing left to
+// index : deleted, moved away or no longer selected files and directories
+pub fn load_paths(
+    spec: &Spec,
+    folder: &Path,
+    paths: &[PathBuf],
+) -> Result<(Vec<MarkdownFile>, Vec<String>), Box<dyn std::error::Error>> {
+    let options = ChunkOptions::from_spec(spec)?;
+    let selector = FileSelector::from_spec(spec)?;
+    let dir = PathBuf::from(spec.docs_folder());
+    let prefix = PathBuf::from(".");
+    let listed = list_files(&dir, &selector)?;
+    let mut selected = BTreeSet::new();
+    let mut removed = Vec::new();
+    for path in paths.iter() {
+        let Ok(relative) = path.strip_prefix(folder) else {
+            continue;
+        };
+        // same form as the paths listed by the walk
+        let path = dir.join(relative);
+        let below: Vec<&PathBuf> = listed
+            .iter()
+            .filter(|file| file.starts_with(&path))
+            .collect();
+        if below.is_empty() {
+            removed.push(file_id(&path, &prefix)?);
+        }
+        selected.extend(below);
+    }
+    let mut files = Vec::new();
+    for path in selected.into_iter() {
+        files.append(&mut load_file(path, &prefix, &options)?);
+    }
+    Ok((files, removed))
+}
+
+// Changed files (file -> hash) whose points written for an older version can
+// be removed, files with failed chunks keep them
+fn stale_files(
+    files: &[MarkdownFile],
+    changed: &[String],
+    failed: &BTreeMap<String, BTreeSet<String>>,
+) -> HashMap<String, String> {
+    let hashes: HashMap<&str, &str> = files
+        .iter()
+        .map(|mkd| (mkd.file.as_str(), mkd.hash.as_str()))
+        .collect();
+    changed
+        .iter()
+        .filter(|file| !failed.contains_key(*file))
+        .filter_map(|file| {
+            hashes
+                .get(file.as_str())
+                .map(|hash| (file.clone(), hash.to_string()))
+        })
+        .collect()
+}
+
+// Chunks not upserted yet by the run the checkpoint belongs to, applied to
+// fitted chunks as splitting renumbers the chunks of a file
+fn pending_chunks(chunks: Vec<MarkdownFile>, checkpoint: &Checkpoint) -> Vec<MarkdownFile> {
+    chunks
+        .into_iter()
+        .filter(|mkd| !checkpoint.is_done(mkd))
+        .collect()
+}
+
+// Fit the chunks to the token limits, then embed and upsert the ones the
+// checkpoint doesn't list as done
+async fn embed_chunks(
+    store: &dyn VectorStore,
+    spec: &Spec,
+    collection: String,
+    chunks: Vec<MarkdownFile>,
+    embedder: Arc<Embedder>,
+    checkpoint: Checkpoint,
+) -> Result<PipelineResult, Box<dyn std::error::Error>> {
+    let workers = spec.embedding_workers.unwrap_or(DEFAULT_EMBEDDING_WORKERS);
+    let batch_size = spec.upsert_batch_size.unwrap_or(DEFAULT_UPSERT_BATCH_SIZE);
+    let limits = TokenLimits::from_spec(spec);
+    let chunks = match TokenCounter::from_spec(spec)? {
+        Some(counter) => fit_chunks(&counter, chunks, &limits, workers).await?,
+        // other providers rely on chunkSize to stay within the model context
+        None => chunks,
+    };
+    let chunks = pending_chunks(chunks, &checkpoint);
+    log::info!(
+        "embedding {} chunks with {} workers (batch size {})",
+        chunks.len(),
+        workers,
+        batch_size
+    );
+    Ok(run_pipeline(
+        store, collection, chunks, embedder, workers, batch_size, checkpoint,
+    )
+    .await)
+}
+
+// Load and chunk the files of every category without touching the store or
+// the embedding server, chunk splits are estimated from the word counts
+pub fn dry_run_categories(spec: &Spec) -> Vec<IndexSummary> {
+    let mut summaries = Vec::new();
+    for category in spec.categories().iter() {
+        let now = Instant::now();
+        let res = load_chunks(category);
+        let summary = match res {
+            Ok(files) => {
+                let unique: BTreeSet<&str> = files.iter().map(|mkd| mkd.file.as_str()).collect();
+                let files_count = unique.len();
+                let chunks = estimate_chunks(files, &TokenLimits::from_spec(category));
+                log::info!(
+                    "dry run {} : {} files would produce about {} chunks",
+                    category.category,
+                    files_count,
+                    chunks.len()
+                );
+                IndexSummary {
+                    category: category.category.clone(),
+                    files: files_count,
+                    chunks: chunks.len(),
+                    elapsed: now.elapsed(),
+                    ..Default::default()
+                }
+            }
+            Err(err) => {
+                log::error!("dry run {} {}", category.category, err);
+                IndexSummary {
+                    category: category.category.clone(),
+                    elapsed: now.elapsed(),
+                    error: Some(err.to_string()),
+                    ..Default::default()
+                }
+            }
+        };
+        summaries.push(summary);
+    }
+    summaries
+}
+
+// Index every configured category, a failing category does not stop the
+// remaining ones from being indexed
+pub async fn index_categories(
+    store: &dyn VectorStore,
+    spec: &Spec,
+    embedder: Arc<Embedder>,
+    mode: IndexMode,
+) -> Vec<IndexSummary> {
+    let mut summaries = Vec::new();
+    for category in spec.categories().iter() {
+        log::info!("indexing category {}", category.category);
+        let now = Instant::now();
+        let res = index_collection(store, category, embedder.clone(), mode).await;
+        let summary = match res {
+            Ok(summary) => summary,
+            Err(err) => {
+                log::error!("indexing {} {}", category.category, err);
+                IndexSummary {
+                    category: category.category.clone(),
+                    elapsed: now.elapsed(),
+                    error: Some(err.to_string()),
+                    ..Default::default()
+                }
+            }
+        };
+        summaries.push(summary);
+    }
+    summaries
+}
+
+// Print a summary table with a row per category
+pub fn print_summary(summaries: &[IndexSummary]) {
+    println!(
+        "{:<20} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>10}  status",
+        "category", "files", "chunks", "embedded", "upserted", "deleted", "failed", "time"
+    );
+    for summary in summaries.iter() {
+        let status = match (&summary.error, summary.failed.is_empty()) {
+            (Some(err), _) => format!("error : {}", err),
+            (None, false) => "incomplete".to_string(),
+            (None, true) => "ok".to_string(),
+        };
+        println!(
+            "{:<20} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>10.2?}  {}",
+            summary.category,
+            summary.files,
+            summary.chunks,
+            summary.embedded,
+            summary.upserted,
+            summary.deleted,
+            summary.failed.len(),
+            summary.elapsed,
+            status
+        );
+    }
+}
+
+// Index all files for the configured category, when incremental is set only
+// new or changed files are embedded and points for deleted files are removed
+pub async fn index_collection(
+    store: &dyn VectorStore,
+    spec: &Spec,
+    embedder: Arc<Embedder>,
+    mode: IndexMode,
+) -> Result<IndexSummary, Box<dyn std::error::Error>> {
+    let now = Instant::now();
+    let files = load_chunks(spec)?;
+
+    log::debug!("markdown batch {:?}", files);
+
+    let category = spec.category.clone();
+    let unique: BTreeSet<&str> = files.iter().map(|mkd| mkd.file.as_str()).collect();
+
+    let metadata = CollectionMetadata::from_spec(spec, embedder.dimension().await?);
+    // collection written by this run and whether the category alias is
+    // switched to it once the run is complete
+    let active = active_collection(store, &category).await?;
+    let (collection, switch) = if mode.incremental {
+        match active {
+            Some(active) => (active, false),
+            None => (next_version(store, &category).await?, true),
+        }
+    } else if mode.resume {
+        // continue the newest version when no run completed it, otherwise
+        // (i.e. after a rollback) the searched collection and the alias stay
+        match (unfinished_version(store, &category).await?, active) {
+            (Some(newest), _) => (newest, true),
+            (None, Some(active)) => (active, false),
+            (None, None) => (next_version(store, &category).await?, true),
+        }
+    } else {
+        (next_version(store, &category).await?, true)
+    };
+    log::info!("indexing into collection {}", collection);
+
+    let checkpoint_dir = spec
+        .checkpoint_dir
+        .clone()
+        .unwrap_or(DEFAULT_CHECKPOINT_DIR.to_string());
+    let plan = if mode.incremental {
+        store
+            .ensure_collection(collection.clone(), &metadata)
+            .await?;
+        let stored = store.read_metadata(collection.clone()).await?;
+        verify_metadata(&collection, stored, &metadata, spec.warn_on_mismatch())?;
+        let indexed = store.indexed_files(collection.clone()).await?;
+        let mut plan = plan_changes(&files, &indexed);
+        let previous = Checkpoint::load(&checkpoint_dir, &category)?;
+        plan.retry(&previous.failed_files);
+        log::info!(
+            "incremental : {} changed, {} deleted, {} unchanged",
+            plan.changed.len(),
+            plan.deleted.len(),
+            plan.unchanged.len()
+        );
+        store
+            .delete_files(collection.clone(), plan.deleted.clone())
+            .await?;
+        Some(plan)
+    } else if mode.resume {
+        store
+            .ensure_collection(collection.clone(), &metadata)
+            .await?;
+        let stored = store.read_metadata(collection.clone()).await?;
+        verify_metadata(&collection, stored, &metadata, spec.warn_on_mismatch())?;
+        None
+    } else {
+        store
+            .reset_collection(collection.clone(), &metadata)
+            .await?;
+        None
+    };
+
+    let mut checkpoint = if mode.resume {
+        let checkpoint = Checkpoint::load(&checkpoint_dir, &category)?;
+        log::info!(
+            "resuming : {} chunks done, {} failed previously",
+            checkpoint.done.len(),
+            checkpoint.failed.len()
+        );
+        checkpoint
+    } else {
+        Checkpoint::new(&checkpoint_dir, &category)
+    };
+    if let Some(plan) = plan.as_ref() {
+        // only failures of this run keep the stale points of a changed file
+        checkpoint.retry_files(&plan.changed);
+    }
+
+    let chunks: Vec<MarkdownFile> = files
+        .iter()
+        .filter(|mkd| {
+            plan.as_ref()
+                .is_none_or(|plan| plan.changed.contains(&mkd.file))
+        })
+        .cloned()
+        .collect();
+    let result = embed_chunks(
+        store,
+        spec,
+        collection.clone(),
+        chunks,
+        embedder,
+        checkpoint,
+    )
+    .await?;
+    let elapsed = now.elapsed();
+    log::info!(
+        "indexed {} of {} chunks ({} upserted)",
+        result.embedded,
+        files.len(),
+        result.upserted
+    );
+    log::info!("time to complete indexing : {:.2?}", elapsed);
+    if let Some(plan) = plan.as_ref() {
+        // a changed file may now produce fewer chunks, its leftover points are
+        // removed once every new chunk is upserted. A file with failed chunks
+        // keeps them and is embedded again by the next incremental run
+        let stale = stale_files(&files, &plan.changed, &result.checkpoint.failed_files);
+        store.delete_stale(collection.clone(), stale).await?;
+    }
+    if !result.failed.is_empty() {
+        log::error!("failed to index {:?}", result.failed);
+        log::error!("rerun with --resume to retry the failed chunks");
+        if switch {
+            log::error!(
+                "{} is not searched yet, the alias {} is switched once it is complete",
+                collection,
+                category
+            );
+        }
+    } else {
+        // the run is complete, there is nothing left to resume
+        result.checkpoint.remove()?;
+        if switch {
+            let keep = spec.keep_versions.unwrap_or(DEFAULT_KEEP_VERSIONS);
+            activate(store, &category, &collection, keep).await?;
+        }
+    }
+    Ok(IndexSummary {
+        category,
+        files: unique.len(),
+        chunks: files.len(),
+        embedded: result.embedded,
+        upserted: result.upserted,
+        deleted: plan.map_or(0, |plan| plan.deleted.len()),
+        failed: result.failed,
+        elapsed,
+        error: None,
+    })
+}
+
+// Sync the files below the changed paths (watch mode) into the collection
+// the category alias points to, only these files are read and embedded and
+// points of paths with nothing left to index are removed
+pub async fn index_paths(
+    store: &dyn VectorStore,
+    spec: &Spec,
+    embedder: Arc<Embedder>,
+    folder: &Path,
+    paths: &[PathBuf],
+) -> Result<IndexSummary, Box<dyn std::error::Error>> {
+    let now = Instant::now();
+    let category = spec.category.clone();
+    let active = active_collection(store, &category).await?;
+    let Some(collection) = active.filter(|_| !paths.iter().any(|path| path == folder)) else {
+        // nothing indexed yet or the docs folder itself changed
+        let mode = IndexMode {
+            incremental: true,
+            ..Default::default()
+        };
+        return index_collection(store, spec, embedder, mode).await;
+    };
+    let metadata = CollectionMetadata::from_spec(spec, embedder.dimension().await?);
+    store
+        .ensure_collection(collection.clone(), &metadata)
+        .await?;
+    let stored = store.read_metadata(collection.clone()).await?;
+    verify_metadata(&collection, stored, &metadata, spec.warn_on_mismatch())?;
+
+    let (files, removed) = load_paths(spec, folder, paths)?;
+    let changed: Vec<String> = files
+        .iter()
+        .map(|mkd| mkd.file.clone())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+    log::info!(
+        "sync {} : {} changed files, {} removed paths",
+        collection,
+        changed.len(),
+        removed.len()
+    );
+    store
+        .delete_files(collection.clone(), removed.clone())
+        .await?;
+
+    let checkpoint_dir = spec
+        .checkpoint_dir
+        .clone()
+        .unwrap_or(DEFAULT_CHECKPOINT_DIR.to_string());
+    // failures of these files in earlier runs are retried now, the others
+    // are kept for the next incremental run
+    let mut checkpoint = Checkpoint::load(&checkpoint_dir, &category)?;
+    checkpoint.done.clear();
+    checkpoint.retry_files(&changed);
+    let result = embed_chunks(
+        store,
+        spec,
+        collection.clone(),
+        files.clone(),
+        embedder,
+        checkpoint,
+    )
+    .await?;
+    let stale = stale_files(&files, &changed, &result.checkpoint.failed_files);
+    store.delete_stale(collection.clone(), stale).await?;
+    if result.checkpoint.failed_files.is_empty() {
+        result.checkpoint.remove()?;
+    } else {
+        log::error!("failed to index {:?}", result.failed);
+    }
+    Ok(IndexSummary {
+        category,
+        files: changed.len(),
+        chunks: files.len(),
+        embedded: result.embedded,
+        upserted: result.upserted,
+        deleted: removed.len(),
+        failed: result.failed,
+        elapsed: now.elapsed(),
+        error: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+    use crate::api::schema::ChunkStrategy;
+    use crate::embeddings::options::EmbeddingOptions;
+    use crate::embeddings::provider::EmbeddingProvider;
+    use crate::error::handler::EmbeddingsError;
+    use crate::indexer::tokens::number_pieces;
+    use crate::store::embedded::EmbeddedStore;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn chunk(file: &str, hash: &str) -> MarkdownFile {
+        MarkdownFile {
+            path: file.to_string(),
+            file: file.to_string(),
+            hash: hash.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // embeds the text length, fails the "steps 1" chunks while fail is set
+    struct FlakyProvider {
+        fail: Arc<AtomicBool>,
+    }
+
+    #[async_trait]
+    impl EmbeddingProvider for FlakyProvider {
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        async fn embed(&self, content: String) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+            if self.fail.load(Ordering::SeqCst) && content.contains("steps 1") {
+                return Err(Box::new(EmbeddingsError::new("timeout")));
+            }
+            Ok(vec![content.len() as f32])
+        }
+
+        async fn embed_batch(
+            &self,
+            contents: Vec<String>,
+        ) -> Result<Vec<Vec<f32>>, Box<dyn std::error::Error>> {
+            let mut embeddings = Vec::new();
+            for content in contents.into_iter() {
+                embeddings.push(self.embed(content).await?);
+            }
+            Ok(embeddings)
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_failed_file_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = EmbeddedStore::open(dir.path().join("store.redb").to_str().unwrap()).unwrap();
+        let checkpoints = dir.path().to_str().unwrap();
+        let metadata = CollectionMetadata {
+            dimension: 1,
+            ..Default::default()
+        };
+        store
+            .reset_collection("docs".to_string(), &metadata)
+            .await
+            .unwrap();
+        let fail = Arc::new(AtomicBool::new(false));
+        let provider = FlakyProvider { fail: fail.clone() };
+        let embedder = Arc::new(Embedder::new(
+            Box::new(provider),
+            "test".to_string(),
+            None,
+            4,
+            EmbeddingOptions::default(),
+        ));
+        let version = |hash: &str, count: usize| -> Vec<MarkdownFile> {
+            (0..count)
+                .map(|n| MarkdownFile {
+                    path: format!("a.md-{}", n),
+                    chunk: n,
+                    contents: format!("{} steps {}", hash, n),
+                    strategy: ChunkStrategy::Words,
+                    ..chunk("a.md", hash)
+                })
+                .collect()
+        };
+        let run = |chunks: Vec<MarkdownFile>, checkpoint: Checkpoint| {
+            run_pipeline(
+                &store,
+                "docs".to_string(),
+                chunks,
+                embedder.clone(),
+                1,
+                8,
+                checkpoint,
+            )
+        };
+        run(version("old", 3), Checkpoint::new(checkpoints, "docs")).await;
+
+        // a.md shrank to two chunks and one of them fails, the old points stay
+        let files = version("new", 2);
+        let changed = vec!["a.md".to_string()];
+        fail.store(true, Ordering::SeqCst);
+        let result = run(files.clone(), Checkpoint::new(checkpoints, "docs")).await;
+        assert_eq!(result.failed, vec!["a.md-1"]);
+        assert!(stale_files(&files, &changed, &result.checkpoint.failed_files).is_empty());
+
+        // the resumed run embeds the failed chunk and removes the old points
+        fail.store(false, Ordering::SeqCst);
+        let checkpoint = Checkpoint::load(checkpoints, "docs").unwrap();
+        let result = run(pending_chunks(files.clone(), &checkpoint), checkpoint).await;
+        assert_eq!(result.embedded, 1);
+        assert!(result.checkpoint.failed_files.is_empty());
+        let stale = stale_files(&files, &changed, &result.checkpoint.failed_files);
+        store.delete_stale("docs".to_string(), stale).await.unwrap();
+        let hashes: Vec<String> = store
+            .scroll("docs".to_string(), &["hash"])
+            .await
+            .unwrap()
+            .iter()
+            .map(|payload| payload["hash"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(hashes, vec!["new", "new"]);
+    }
+
+    #[test]
+    fn resume_split_file_pass() {
+        // the second chunk of a.md is split in two by the token limits
+        let piece = |path: &str, contents: &str| MarkdownFile {
+            path: path.to_string(),
+            contents: contents.to_string(),
+            ..chunk("a.md", "1")
+        };
+        let fit = || {
+            number_pieces(vec![
+                vec![piece("a.md-0", "intro")],
+                vec![piece("a.md-1", "steps 1"), piece("a.md-1", "steps 2")],
+                vec![chunk("b.md", "2")],
+            ])
+        };
+        let fitted = fit();
+        let paths: Vec<&str> = fitted.iter().map(|mkd| mkd.path.as_str()).collect();
+        assert_eq!(paths, vec!["a.md-0", "a.md-1", "a.md-2", "b.md"]);
+        assert_eq!(fitted[2].chunk, 2);
+        for mkd in fitted.iter() {
+            assert_eq!(mkd.file, mkd.path.split('-').next().unwrap());
+        }
+
+        // the interrupted run upserted the first two pieces of a.md
+        let mut checkpoint = Checkpoint::new("unused", "docs");
+        for mkd in fitted.iter().take(2) {
+            checkpoint.mark_done(mkd.path.clone(), mkd.file.clone(), mkd.hash.clone());
+        }
+        let pending = pending_chunks(fit(), &checkpoint);
+        let paths: Vec<&str> = pending.iter().map(|mkd| mkd.path.as_str()).collect();
+        assert_eq!(paths, vec!["a.md-2", "b.md"]);
+        assert_eq!(pending[0].contents, "steps 2");
+    }
+
+    #[test]
+    fn plan_changes_pass() {
+        let files = vec![chunk("a.sh", "1"), chunk("b.sh", "2"), chunk("c.sh", "3")];
+        let mut indexed = HashMap::new();
+        for (file, hash) in [("a.sh", "1"), ("b.sh", "old"), ("d.sh", "4")] {
+            indexed.insert(
+                file.to_string(),
+                IndexedFile {
+                    hash: hash.to_string(),
+                    mtime: 0,
+                },
+            );
+        }
+        let mut plan = plan_changes(&files, &indexed);
+        assert_eq!(plan.unchanged, vec!["a.sh"]);
+        assert_eq!(plan.changed, vec!["b.sh", "c.sh"]);
+        assert_eq!(plan.deleted, vec!["d.sh"]);
+
+        plan.retry(&BTreeMap::from([("a.sh".to_string(), BTreeSet::new())]));
+        assert!(plan.unchanged.is_empty());
+        assert_eq!(plan.changed, vec!["a.sh", "b.sh", "c.sh"]);
+
+        // b.sh had a failed chunk, its older points are kept
+        let failed = BTreeMap::from([("b.sh".to_string(), BTreeSet::new())]);
+        let stale = stale_files(&files, &plan.changed, &failed);
+        assert_eq!(stale.len(), 2);
+        assert_eq!(stale["c.sh"], "3");
+        assert!(!stale.contains_key("b.sh"));
+    }
+}
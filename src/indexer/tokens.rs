@@ -0,0 +1,298 @@
+use crate::api::schema::{ChunkStrategy, EmbeddingProviderKind, Spec};
+use crate::embeddings::client::EmbeddingClient;
+use crate::embeddings::options::EmbeddingOptions;
+use crate::llamacpp::tokenize::{detokenize, tokenize};
+use crate::markdown::process::{
+    windows, MarkdownFile, DEFAULT_CHUNK_OVERLAP, DEFAULT_CHUNK_SIZE, WORDS_PER_100_TOKENS,
+};
+use custom_logger as log;
+use futures::stream::{self, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::sync::Once;
+
+// tokens the embedding server adds around every input (i.e. [CLS] and [SEP])
+const SPECIAL_TOKENS: usize = 2;
+// embedding model context length when not set in the config
+pub const DEFAULT_MAX_TOKENS: usize = 512;
+
+// the warning for providers without a tokenizer is only logged once per run
+static UNCHECKED: Once = Once::new();
+
+// Token limits applied to every chunk before it is embedded
+pub struct TokenLimits {
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    // embedding model context length
+    pub max_tokens: usize,
+    // added by the embedder in front of every chunk
+    pub document_prefix: String,
+}
+
+impl TokenLimits {
+    pub fn from_spec(spec: &Spec) -> Self {
+        Self {
+            chunk_size: spec.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+            chunk_overlap: spec.chunk_overlap.unwrap_or(DEFAULT_CHUNK_OVERLAP),
+            max_tokens: spec.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            document_prefix: EmbeddingOptions::from_spec(spec).document_prefix,
+        }
+    }
+
+    // largest number of tokens a chunk may have, prefix_tokens is the length
+    // of the document prefix that still goes in front of it
+    pub fn limit(&self, prefix_tokens: usize) -> usize {
+        self.chunk_size
+            .min(
+                self.max_tokens
+                    .saturating_sub(SPECIAL_TOKENS + prefix_tokens),
+            )
+            .max(1)
+    }
+}
+
+// The llama.cpp server used to count tokens, requests go through the
+// embedding client (timeouts, retries and rate limit)
+pub struct TokenCounter {
+    client: EmbeddingClient,
+    server: String,
+}
+
+impl TokenCounter {
+    // none (with a warning) when the embedding provider has no /tokenize
+    // endpoint, chunks are then embedded without checking the limits
+    pub fn from_spec(spec: &Spec) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let Some(server) = spec.tokenizer_server() else {
+            warn_unchecked(spec);
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            client: EmbeddingClient::from_spec(spec)?,
+            server,
+        }))
+    }
+
+    async fn tokenize(&self, content: String) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+        Ok(tokenize(&self.client, &self.server, content).await?)
+    }
+
+    async fn detokenize(&self, tokens: Vec<u32>) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(detokenize(&self.client, &self.server, tokens).await?)
+    }
+}
+
+fn warn_unchecked(spec: &Spec) {
+    UNCHECKED.call_once(
+        || match spec.embedding_provider.clone().unwrap_or_default() {
+            EmbeddingProviderKind::Local => log::warn!(
+                "local embeddings : chunks are not checked against the token limits, \
+             the model tokenizer truncates them at maxTokens"
+            ),
+            provider => log::warn!(
+                "{:?} embeddings : chunks are not checked against the token limits (only llamacpp \
+             exposes /tokenize), keep chunkSize below the model context",
+                provider
+            ),
+        },
+    );
+}
+
+// Make sure no chunk goes over the token limit : chunks that are too long are
+// split into overlapping token windows (headers are truncated), chunks of a
+// file that got split are renumbered so point ids stay unique
+pub async fn fit_chunks(
+    counter: &TokenCounter,
+    chunks: Vec<MarkdownFile>,
+    limits: &TokenLimits,
+    concurrency: usize,
+) -> Result<Vec<MarkdownFile>, Box<dyn std::error::Error>> {
+    let mut prefix_tokens = 0;
+    if !limits.document_prefix.is_empty() {
+        prefix_tokens = counter
+            .tokenize(limits.document_prefix.clone())
+            .await?
+            .len();
+    }
+    let limit = limits.limit(prefix_tokens);
+    let results: Vec<Result<Vec<MarkdownFile>, Box<dyn std::error::Error>>> = stream::iter(chunks)
+        .map(|mkd| fit_chunk(counter, mkd, limit, limits.chunk_overlap))
+        .buffered(concurrency.max(1))
+        .collect()
+        .await;
+
+    let pieces = results.into_iter().collect::<Result<Vec<_>, _>>()?;
+    Ok(number_pieces(pieces))
+}
+
+// Flatten the pieces of every chunk, the chunks of a file with a split chunk
+// are numbered again over the whole file. The numbering only depends on the
+// file contents so it is the same in every run of the same file
+pub fn number_pieces(pieces: Vec<Vec<MarkdownFile>>) -> Vec<MarkdownFile> {
+    let split: HashSet<String> = pieces
+        .iter()
+        .filter(|chunk| chunk.len() > 1)
+        .map(|chunk| chunk[0].file.clone())
+        .collect();
+    let mut fitted: Vec<MarkdownFile> = pieces.into_iter().flatten().collect();
+    let mut counters: HashMap<String, usize> = HashMap::new();
+    for mkd in fitted.iter_mut().filter(|mkd| split.contains(&mkd.file)) {
+        let counter = counters.entry(mkd.file.clone()).or_insert(0);
+        mkd.chunk = *counter;
+        mkd.path = format!("{}-{}", mkd.file, counter);
+        *counter += 1;
+    }
+    fitted
+}
+
+// Split the chunks like fit_chunks without a tokenizer, the token counts are
+// estimated from the word counts (used by inspect and dry runs)
+pub fn estimate_chunks(chunks: Vec<MarkdownFile>, limits: &TokenLimits) -> Vec<MarkdownFile> {
+    let words = |tokens: usize| (tokens * WORDS_PER_100_TOKENS / 100).max(1);
+    let prefix_words = limits.document_prefix.split_whitespace().count();
+    let prefix_tokens = prefix_words * 100 / WORDS_PER_100_TOKENS;
+    let size = words(limits.limit(prefix_tokens));
+    let overlap = (limits.chunk_overlap * WORDS_PER_100_TOKENS / 100).min(size / 2);
+    let pieces = chunks
+        .into_iter()
+        .map(|mkd| estimate_chunk(mkd, size, overlap))
+        .collect();
+    number_pieces(pieces)
+}
+
+fn estimate_chunk(mkd: MarkdownFile, size: usize, overlap: usize) -> Vec<MarkdownFile> {
+    let text = mkd.embedding_text();
+    let words: Vec<&str> = text.split_whitespace().collect();
+    if words.len() <= size {
+        return vec![mkd];
+    }
+    if mkd.strategy == ChunkStrategy::Headers {
+        let mut mkd = mkd;
+        mkd.headers = Some(words[..size].join(" "));
+        return vec![mkd];
+    }
+    windows(words.len(), size, overlap)
+        .into_iter()
+        .map(|(from, to)| {
+            let mut piece = mkd.clone();
+            piece.contents = words[from..to].join(" ");
+            piece
+        })
+        .collect()
+}
+
+async fn fit_chunk(
+    counter: &TokenCounter,
+    mkd: MarkdownFile,
+    limit: usize,
+    chunk_overlap: usize,
+) -> Result<Vec<MarkdownFile>, Box<dyn std::error::Error>> {
+    let tokens = counter.tokenize(mkd.embedding_text()).await?;
+    if tokens.len() <= limit {
+        return Ok(vec![mkd]);
+    }
+
+    if mkd.strategy == ChunkStrategy::Headers {
+        log::warn!(
+            "header for {} truncated from {} to {} tokens",
+            mkd.path,
+            tokens.len(),
+            limit
+        );
+        let mut mkd = mkd;
+        mkd.headers = Some(counter.detokenize(tokens[..limit].to_vec()).await?);
+        return Ok(vec![mkd]);
+    }
+
+    // the section breadcrumb is repeated at the start of every piece
+    let prefix = mkd
+        .breadcrumb
+        .as_ref()
+        .map(|breadcrumb| format!("{}\n\n", breadcrumb))
+        .unwrap_or_default();
+    let body = mkd
+        .contents
+        .strip_prefix(&prefix)
+        .unwrap_or(&mkd.contents)
+        .to_string();
+    let mut prefix_tokens = 0;
+    if !prefix.is_empty() {
+        // one extra token as tokenizing the parts separately may differ by one
+        prefix_tokens = counter.tokenize(prefix.clone()).await?.len() + 1;
+    }
+    // a breadcrumb taking up more than half the limit is not repeated
+    let (prefix, tokens, size) = if prefix_tokens < limit / 2 {
+        let body_tokens = counter.tokenize(body).await?;
+        (prefix, body_tokens, limit - prefix_tokens)
+    } else {
+        (String::new(), tokens, limit)
+    };
+    let overlap = chunk_overlap.min(size / 2);
+
+    let mut pieces = Vec::new();
+    for (from, to) in windows(tokens.len(), size, overlap).into_iter() {
+        let text = counter.detokenize(tokens[from..to].to_vec()).await?;
+        let mut piece = mkd.clone();
+        piece.contents = format!("{}{}", prefix, text);
+        pieces.push(piece);
+    }
+    log::debug!(
+        "split {} ({} tokens) into {} chunks",
+        mkd.path,
+        tokens.len(),
+        pieces.len()
+    );
+    Ok(pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn token_limits_pass() {
+        let limits = TokenLimits {
+            chunk_size: 600,
+            chunk_overlap: 20,
+            max_tokens: 512,
+            document_prefix: "passage: ".to_string(),
+        };
+        assert_eq!(limits.limit(0), 510);
+        // the document prefix takes room from the model context
+        assert_eq!(limits.limit(3), 507);
+        let small = TokenLimits {
+            chunk_size: 200,
+            ..limits
+        };
+        assert_eq!(small.limit(3), 200);
+
+        // 8 tokens are about 6 words
+        let tiny = TokenLimits {
+            chunk_size: 8,
+            chunk_overlap: 0,
+            max_tokens: 512,
+            document_prefix: String::new(),
+        };
+        let text = (0..15).map(|n| n.to_string()).collect::<Vec<_>>().join(" ");
+        let chunks = vec![
+            MarkdownFile {
+                path: "a.md".to_string(),
+                file: "a.md".to_string(),
+                contents: text.clone(),
+                strategy: ChunkStrategy::Words,
+                ..Default::default()
+            },
+            MarkdownFile {
+                path: "b.sh".to_string(),
+                file: "b.sh".to_string(),
+                headers: Some(text),
+                strategy: ChunkStrategy::Headers,
+                ..Default::default()
+            },
+        ];
+        let estimated = estimate_chunks(chunks, &tiny);
+        let paths: Vec<&str> = estimated.iter().map(|mkd| mkd.path.as_str()).collect();
+        assert_eq!(paths, vec!["a.md-0", "a.md-1", "a.md-2", "b.sh"]);
+        assert_eq!(estimated[2].contents, "12 13 14");
+        assert_eq!(estimated[3].headers.as_deref(), Some("0 1 2 3 4 5"));
+    }
+}
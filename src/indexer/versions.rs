@@ -0,0 +1,277 @@
+use crate::api::schema::Spec;
+use crate::error::handler::EmbeddingsError;
+use crate::store::vector::VectorStore;
+use custom_logger as log;
+
+// previous versions kept after a switch when not set in the config
+pub const DEFAULT_KEEP_VERSIONS: usize = 2;
+
+// Full re-indexes build into "<category>_v<n>", the alias named after the
+// category is switched to the new version once it is complete so searches
+// never see a half built collection
+pub fn version_name(category: &str, version: u32) -> String {
+    format!("{}_v{}", category, version)
+}
+
+fn parse_version(category: &str, name: &str) -> Option<u32> {
+    name.strip_prefix(category)?
+        .strip_prefix("_v")?
+        .parse()
+        .ok()
+}
+
+// versions of the category in the store, oldest first
+pub async fn list_versions(
+    store: &dyn VectorStore,
+    category: &str,
+) -> Result<Vec<u32>, Box<dyn std::error::Error>> {
+    let mut versions: Vec<u32> = store
+        .list_collections()
+        .await?
+        .iter()
+        .filter_map(|name| parse_version(category, name))
+        .collect();
+    versions.sort();
+    Ok(versions)
+}
+
+// collection searched for the category : the alias target or a collection
+// named after the category (indexed before versioning), none when neither exists
+pub async fn active_collection(
+    store: &dyn VectorStore,
+    category: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if let Some(target) = store.alias_target(category.to_string()).await? {
+        return Ok(Some(target));
+    }
+    let collections = store.list_collections().await?;
+    Ok(collections.into_iter().find(|name| name == category))
+}
+
+// true once a complete run activated the version
+async fn is_activated(
+    store: &dyn VectorStore,
+    collection: &str,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let metadata = store.read_metadata(collection.to_string()).await?;
+    Ok(metadata.is_some_and(|metadata| metadata.activated))
+}
+
+// the newest version when no run completed it (an interrupted or failed
+// full run), a resumed run continues it and activates it once complete
+pub async fn unfinished_version(
+    store: &dyn VectorStore,
+    category: &str,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let versions = list_versions(store, category).await?;
+    let Some(newest) = versions.last() else {
+        return Ok(None);
+    };
+    let name = version_name(category, *newest);
+    if is_activated(store, &name).await? {
+        return Ok(None);
+    }
+    Ok(Some(name))
+}
+
+// name of the version a full re-index builds into
+pub async fn next_version(
+    store: &dyn VectorStore,
+    category: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let versions = list_versions(store, category).await?;
+    let next = versions.last().map_or(1, |last| last + 1);
+    Ok(version_name(category, next))
+}
+
+// Mark the collection as complete and point the category alias at it, then
+// delete the unfinished versions older than it and the activated versions
+// beyond the newest keep ones (the active version is never deleted)
+pub async fn activate(
+    store: &dyn VectorStore,
+    category: &str,
+    collection: &str,
+    keep: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(mut metadata) = store.read_metadata(collection.to_string()).await? {
+        metadata.activated = true;
+        store
+            .write_metadata(collection.to_string(), &metadata)
+            .await?;
+    }
+
+    let collections = store.list_collections().await?;
+    let legacy = collections.iter().any(|name| name == category);
+    let switched = store
+        .switch_alias(category.to_string(), collection.to_string())
+        .await;
+    if legacy {
+        // the collection indexed before versioning is replaced by the alias,
+        // a store that refuses an alias named like a collection gets it once
+        // the collection is gone
+        log::warn!("replacing collection {} with an alias", category);
+        store.delete_collection(category.to_string()).await?;
+        if switched.is_err() {
+            store
+                .switch_alias(category.to_string(), collection.to_string())
+                .await?;
+        }
+    } else {
+        switched?;
+    }
+    log::info!("alias {} switched to {}", category, collection);
+
+    let current = parse_version(category, collection).unwrap_or(0);
+    let mut kept = 0;
+    for version in list_versions(store, category).await?.into_iter().rev() {
+        let name = version_name(category, version);
+        if name == collection {
+            continue;
+        }
+        let stale = if is_activated(store, &name).await? {
+            kept += 1;
+            kept > keep
+        } else {
+            version < current
+        };
+        if stale {
+            store.delete_collection(name).await?;
+        }
+    }
+    Ok(())
+}
+
+// Switch the category alias back to the newest activated version older than
+// the active one, returns the collection now searched
+pub async fn rollback(
+    store: &dyn VectorStore,
+    category: &str,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let active = store.alias_target(category.to_string()).await?;
+    let current = active
+        .as_deref()
+        .and_then(|name| parse_version(category, name));
+    let Some(current) = current else {
+        return Err(Box::new(EmbeddingsError::new(&format!(
+            "category {} has no versioned collection to roll back from",
+            category
+        ))));
+    };
+    let mut previous = None;
+    for version in list_versions(store, category).await?.into_iter().rev() {
+        if version < current && is_activated(store, &version_name(category, version)).await? {
+            previous = Some(version);
+            break;
+        }
+    }
+    let Some(previous) = previous else {
+        return Err(Box::new(EmbeddingsError::new(&format!(
+            "category {} has no version older than {}",
+            category,
+            version_name(category, current)
+        ))));
+    };
+    let collection = version_name(category, previous);
+    store
+        .switch_alias(category.to_string(), collection.clone())
+        .await?;
+    log::info!(
+        "alias {} rolled back from {} to {}",
+        category,
+        version_name(category, current),
+        collection
+    );
+    Ok(collection)
+}
+
+// Roll back every configured category, a failing category does not stop the
+// remaining ones
+pub async fn rollback_categories(store: &dyn VectorStore, spec: &Spec) -> usize {
+    let mut failed = 0;
+    for category in spec.categories().iter() {
+        if let Err(err) = rollback(store, &category.category).await {
+            log::error!("rollback {}", err);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+    use crate::qdrant::metadata::CollectionMetadata;
+    use crate::store::embedded::EmbeddedStore;
+
+    #[tokio::test]
+    async fn versions_pass() {
+        assert_eq!(parse_version("scripts", "scripts_v12"), Some(12));
+        assert_eq!(parse_version("scripts", "scripts_docs_v1"), None);
+        assert_eq!(parse_version("scripts", "scripts"), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let store = EmbeddedStore::open(dir.path().join("store.redb").to_str().unwrap()).unwrap();
+        let metadata = CollectionMetadata {
+            dimension: 2,
+            ..Default::default()
+        };
+        // a collection indexed before versioning is replaced by the alias
+        store
+            .reset_collection("scripts".to_string(), &metadata)
+            .await
+            .unwrap();
+        assert_eq!(
+            active_collection(&store, "scripts").await.unwrap(),
+            Some("scripts".to_string())
+        );
+        for _ in 0..4 {
+            let name = next_version(&store, "scripts").await.unwrap();
+            store
+                .reset_collection(name.clone(), &metadata)
+                .await
+                .unwrap();
+            activate(&store, "scripts", &name, 2).await.unwrap();
+        }
+        assert_eq!(
+            list_versions(&store, "scripts").await.unwrap(),
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            active_collection(&store, "scripts").await.unwrap(),
+            Some("scripts_v4".to_string())
+        );
+        assert!(store
+            .check_vector_size("scripts".to_string(), 2)
+            .await
+            .is_ok());
+
+        assert_eq!(unfinished_version(&store, "scripts").await.unwrap(), None);
+
+        // a failed full run is never kept as a version nor rolled back to
+        store
+            .reset_collection("scripts_v5".to_string(), &metadata)
+            .await
+            .unwrap();
+        assert_eq!(
+            unfinished_version(&store, "scripts").await.unwrap(),
+            Some("scripts_v5".to_string())
+        );
+        let name = next_version(&store, "scripts").await.unwrap();
+        store
+            .reset_collection(name.clone(), &metadata)
+            .await
+            .unwrap();
+        activate(&store, "scripts", &name, 2).await.unwrap();
+        assert_eq!(
+            list_versions(&store, "scripts").await.unwrap(),
+            vec![3, 4, 6]
+        );
+
+        // after a rollback a resumed run has nothing to continue
+        assert_eq!(rollback(&store, "scripts").await.unwrap(), "scripts_v4");
+        assert_eq!(unfinished_version(&store, "scripts").await.unwrap(), None);
+        assert_eq!(rollback(&store, "scripts").await.unwrap(), "scripts_v3");
+        assert!(rollback(&store, "scripts").await.is_err());
+    }
+}
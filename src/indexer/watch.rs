@@ -0,0 +1,111 @@
+use crate::api::schema::Spec;
+use crate::embeddings::embedder::Embedder;
+use crate::indexer::process::{index_collection, index_paths, IndexMode, IndexSummary};
+use crate::store::vector::VectorStore;
+use custom_logger as log;
+use notify_debouncer_mini::notify::RecursiveMode;
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+// debounce period when not set in the config
+const DEFAULT_WATCH_DEBOUNCE_MS: u64 = 500;
+// changed paths queued between syncs
+const WATCH_QUEUE: usize = 1024;
+
+// Watch the docs folder of every category and keep the collections in sync,
+// bursts of changes are debounced and each sync only reads and re-embeds the
+// changed paths and removes points for deleted files or directories
+pub async fn watch_categories(
+    store: &dyn VectorStore,
+    spec: &Spec,
+    embedder: Arc<Embedder>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let categories = spec.categories();
+    let debounce = spec.watch_debounce_ms.unwrap_or(DEFAULT_WATCH_DEBOUNCE_MS);
+
+    let (tx, mut rx) = mpsc::channel::<PathBuf>(WATCH_QUEUE);
+    let mut debouncer = new_debouncer(
+        Duration::from_millis(debounce),
+        move |res: DebounceEventResult| match res {
+            Ok(events) => {
+                for event in events.into_iter() {
+                    // the watcher runs on its own thread, not on the runtime
+                    if tx.blocking_send(event.path).is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(err) => log::error!("watch {}", err),
+        },
+    )?;
+
+    // watched folders are canonical so the event paths can be matched
+    let mut folders = Vec::new();
+    for category in categories.iter() {
+        let folder = fs::canonicalize(category.docs_folder())?;
+        debouncer
+            .watcher()
+            .watch(&folder, RecursiveMode::Recursive)?;
+        log::info!("watching {:?} for category {}", folder, category.category);
+        folders.push(folder);
+    }
+
+    // bring the collections in sync before waiting for changes
+    for category in categories.iter() {
+        let mode = IndexMode {
+            incremental: true,
+            ..Default::default()
+        };
+        let res = index_collection(store, category, embedder.clone(), mode).await;
+        log_sync(category, res);
+    }
+
+    while let Some(path) = rx.recv().await {
+        let mut paths = vec![path];
+        while let Ok(path) = rx.try_recv() {
+            paths.push(path);
+        }
+        log::debug!("changed paths {:?}", paths);
+        for (category, folder) in categories.iter().zip(folders.iter()) {
+            let changed: Vec<PathBuf> = paths
+                .iter()
+                .filter(|path| path.starts_with(folder))
+                .cloned()
+                .collect();
+            if !changed.is_empty() {
+                sync_paths(store, category, embedder.clone(), folder, &changed).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn sync_paths(
+    store: &dyn VectorStore,
+    category: &Spec,
+    embedder: Arc<Embedder>,
+    folder: &Path,
+    paths: &[PathBuf],
+) {
+    let res = index_paths(store, category, embedder, folder, paths).await;
+    log_sync(category, res);
+}
+
+fn log_sync(category: &Spec, res: Result<IndexSummary, Box<dyn std::error::Error>>) {
+    match res {
+        Ok(summary) => log::info!(
+            "sync {} : {} chunks embedded, {} upserted, {} files deleted, {} failed in {:.2?}",
+            summary.category,
+            summary.embedded,
+            summary.upserted,
+            summary.deleted,
+            summary.failed.len(),
+            summary.elapsed
+        ),
+        Err(err) => log::error!("sync {} {}", category.category, err),
+    }
+}
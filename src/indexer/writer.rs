@@ -0,0 +1,93 @@
+use crate::indexer::checkpoint::Checkpoint;
+use crate::markdown::process::MarkdownFile;
+use crate::store::vector::{StorePoint, VectorStore};
+use custom_logger as log;
+
+// Collects points and upserts them to the store in batches, a failed batch is
+// logged and recorded so the remaining batches still get written. The
+// checkpoint is saved after every batch
+pub struct BatchWriter<'a> {
+    store: &'a dyn VectorStore,
+    collection: String,
+    batch_size: usize,
+    points: Vec<StorePoint>,
+    // (chunk path, source file, file hash) for each queued point
+    paths: Vec<(String, String, String)>,
+    pub upserted: usize,
+    pub failed: Vec<String>,
+    pub checkpoint: Checkpoint,
+}
+
+impl<'a> BatchWriter<'a> {
+    pub fn new(
+        store: &'a dyn VectorStore,
+        collection: String,
+        batch_size: usize,
+        checkpoint: Checkpoint,
+    ) -> Self {
+        Self {
+            store,
+            collection,
+            batch_size: batch_size.max(1),
+            points: Vec::new(),
+            paths: Vec::new(),
+            upserted: 0,
+            failed: Vec::new(),
+            checkpoint,
+        }
+    }
+
+    // record a chunk that never made it to the writer (i.e. embedding failed)
+    pub fn record_failure(&mut self, mkd: &MarkdownFile, error: String) {
+        self.checkpoint
+            .mark_failed(mkd.path.clone(), mkd.file.clone(), error);
+        self.failed.push(mkd.path.clone());
+    }
+
+    pub async fn push(&mut self, mkd: &MarkdownFile, point: StorePoint) {
+        self.points.push(point);
+        self.paths
+            .push((mkd.path.clone(), mkd.file.clone(), mkd.hash.clone()));
+        if self.points.len() >= self.batch_size {
+            self.flush().await;
+        }
+    }
+
+    // upsert the queued points, the checkpoint is saved even without points
+    // so failures recorded since the last batch are kept
+    pub async fn flush(&mut self) {
+        if !self.points.is_empty() {
+            self.upsert().await;
+        }
+        if let Err(err) = self.checkpoint.save() {
+            log::warn!("unable to save checkpoint {}", err);
+        }
+    }
+
+    async fn upsert(&mut self) {
+        let points = std::mem::take(&mut self.points);
+        let paths = std::mem::take(&mut self.paths);
+        let count = points.len();
+        let res = self
+            .store
+            .upsert_points(self.collection.clone(), points)
+            .await;
+        match res {
+            Ok(_) => {
+                self.upserted += count;
+                log::debug!("upserted batch of {} points", count);
+                for (path, file, hash) in paths.into_iter() {
+                    self.checkpoint.mark_done(path, file, hash);
+                }
+            }
+            Err(err) => {
+                log::error!("upsert batch of {} points failed {}", count, err);
+                for (path, file, _) in paths.into_iter() {
+                    self.checkpoint
+                        .mark_failed(path.clone(), file, err.to_string());
+                    self.failed.push(path);
+                }
+            }
+        }
+    }
+}
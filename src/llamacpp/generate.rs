@@ -1,6 +1,6 @@
+use crate::embeddings::client::EmbeddingClient;
+use crate::error::handler::EmbeddingClientError;
 use custom_logger as log;
-use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
-use reqwest::Client;
 use serde_derive::{Deserialize, Serialize};
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -16,44 +16,88 @@ pub struct Embeddings {
     pub embedding: Vec<Vec<f32>>,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchPayload {
+    pub content: Vec<String>,
+}
+
 pub async fn get_embeddings(
-    url: String,
+    client: &EmbeddingClient,
+    url: &str,
     content: String,
-) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
-    let mut header_map: HeaderMap = HeaderMap::new();
-    header_map.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    header_map.insert(ACCEPT, HeaderValue::from_static("application/json"));
-    let client = Client::new();
+) -> Result<Vec<f32>, EmbeddingClientError> {
     let payload = Payload { content };
-    let json_payload = serde_json::to_string(&payload).unwrap();
-    let res_post = client
-        .post(url.clone())
-        .body(json_payload.clone())
-        .headers(header_map.clone())
-        .send()
-        .await;
-
-    let mut embeddings: Vec<Embeddings> = Vec::new();
-
-    log::trace!("embeddings result {:?}", res_post);
-
-    if res_post.is_ok() {
-        let body = res_post.unwrap().bytes().await;
-        if body.is_ok() {
-            let data = body.unwrap();
-            embeddings = serde_json::from_slice(&data).unwrap();
-            log::debug!(
-                "index {} : embedding {}",
-                embeddings[0].index,
-                embeddings[0].embedding.len()
-            );
-            let mut count = 0;
-            for array in embeddings[0].embedding.iter() {
-                log::debug!("embedding : count {} : {:?}", count, array);
-                count += 1;
-            }
+    let embeddings: Vec<Embeddings> = client.post_json(url, &payload, None).await?;
+    log::trace!("embeddings result {:?}", embeddings);
+    let mut embeddings = map_by_index(url, 1, embeddings)?;
+    let embedding = embeddings.remove(0);
+    log::debug!("embedding {}", embedding.len());
+    Ok(embedding)
+}
+
+// Embed several texts in one request, the results are mapped back to their
+// input by index
+pub async fn get_embeddings_batch(
+    client: &EmbeddingClient,
+    url: &str,
+    contents: Vec<String>,
+) -> Result<Vec<Vec<f32>>, EmbeddingClientError> {
+    let count = contents.len();
+    let payload = BatchPayload { content: contents };
+    let embeddings: Vec<Embeddings> = client.post_json(url, &payload, None).await?;
+    log::debug!("batch of {} : {} embeddings", count, embeddings.len());
+    map_by_index(url, count, embeddings)
+}
+
+// llama.cpp returns one (pooled) row per input, every input must get a non
+// empty embedding
+fn map_by_index(
+    url: &str,
+    count: usize,
+    embeddings: Vec<Embeddings>,
+) -> Result<Vec<Vec<f32>>, EmbeddingClientError> {
+    let mut result: Vec<Option<Vec<f32>>> = vec![None; count];
+    for item in embeddings.into_iter() {
+        let embedding = item.embedding.into_iter().next();
+        if let (Some(slot), Some(embedding)) = (result.get_mut(item.index), embedding) {
+            *slot = Some(embedding);
         }
     }
+    result
+        .into_iter()
+        .enumerate()
+        .map(|(index, embedding)| {
+            embedding
+                .filter(|e| !e.is_empty())
+                .ok_or_else(|| EmbeddingClientError::Response {
+                    url: url.to_string(),
+                    details: format!("no embedding for input {}", index),
+                })
+        })
+        .collect()
+}
 
-    Ok(embeddings[0].embedding[0].clone())
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn map_by_index_pass() {
+        let embeddings = vec![
+            Embeddings {
+                index: 1,
+                embedding: vec![vec![0.3, 0.4]],
+            },
+            Embeddings {
+                index: 0,
+                embedding: vec![vec![0.1, 0.2]],
+            },
+        ];
+        let res = map_by_index("url", 2, embeddings.clone()).unwrap();
+        assert_eq!(res, vec![vec![0.1, 0.2], vec![0.3, 0.4]]);
+        assert!(map_by_index("url", 3, embeddings).is_err());
+        assert!(map_by_index("url", 1, vec![]).is_err());
+    }
 }
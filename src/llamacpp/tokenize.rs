@@ -0,0 +1,51 @@
+use crate::embeddings::client::EmbeddingClient;
+use crate::error::handler::EmbeddingClientError;
+use custom_logger as log;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenizeRequest {
+    pub content: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenizeResponse {
+    pub tokens: Vec<u32>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DetokenizeRequest {
+    pub tokens: Vec<u32>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DetokenizeResponse {
+    pub content: String,
+}
+
+// Tokenize content with the llama.cpp server /tokenize endpoint
+pub async fn tokenize(
+    client: &EmbeddingClient,
+    server: &str,
+    content: String,
+) -> Result<Vec<u32>, EmbeddingClientError> {
+    let url = format!("{}/tokenize", server);
+    let data: TokenizeResponse = client
+        .post_json(&url, &TokenizeRequest { content }, None)
+        .await?;
+    log::trace!("tokenize result {:?}", data.tokens);
+    Ok(data.tokens)
+}
+
+// Convert tokens back to text with the llama.cpp server /detokenize endpoint
+pub async fn detokenize(
+    client: &EmbeddingClient,
+    server: &str,
+    tokens: Vec<u32>,
+) -> Result<String, EmbeddingClientError> {
+    let url = format!("{}/detokenize", server);
+    let data: DetokenizeResponse = client
+        .post_json(&url, &DetokenizeRequest { tokens }, None)
+        .await?;
+    Ok(data.content)
+}
@@ -1,28 +1,42 @@
+use crate::cache::store::{print_cache_stats, EmbeddingCache};
 use crate::chat::client::OpenAIClient;
 use crate::chat::process::ChatSession;
+use crate::embeddings::embedder::{Embedder, DEFAULT_EMBEDDING_BATCH_SIZE};
+use crate::embeddings::options::EmbeddingOptions;
+use crate::embeddings::provider::new_provider;
 use crate::error::handler::EmbeddingsError;
+use crate::indexer::inspect::inspect_categories;
+use crate::indexer::process::{dry_run_categories, index_categories, print_summary, IndexMode};
+use crate::indexer::versions::rollback_categories;
+use crate::indexer::watch::watch_categories;
 use crate::markdown::process::*;
+use crate::qdrant::hybrid::HybridOptions;
+use crate::qdrant::metadata::CollectionMetadata;
+use crate::store::vector::new_store;
 use clap::Parser;
 use custom_logger as log;
-use llamacpp::generate::get_embeddings;
-use qdrant_client::Qdrant;
 use std::process::exit;
 use std::sync::Arc;
-use std::time::Instant;
 use std::{fs, str::FromStr};
 
 mod api;
+mod cache;
 mod chat;
+mod embeddings;
 mod error;
+mod indexer;
 mod llamacpp;
 mod markdown;
 mod qdrant;
+mod store;
 
 // local modules
 use api::schema::*;
-use qdrant::client::*;
 
-#[tokio::main(flavor = "current_thread")]
+// embedding cache location when not set in the config
+const DEFAULT_EMBEDDING_CACHE_PATH: &str = ".cache/embeddings.redb";
+
+#[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let args = Cli::parse();
     let cfg = args.config.as_ref().unwrap().to_string();
@@ -42,72 +56,107 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let cfg_data = fs::read_to_string(cfg.clone())?;
     let cfg = serde_json::from_str::<ApplicationConfig>(&cfg_data.clone())?;
 
-    // setup qdrant client
-    let client = Qdrant::from_url(&format!(
-        "{}:{}",
-        cfg.clone().spec.qdrant_url,
-        cfg.clone().spec.qdrant_port
-    ))
-    .build();
-
-    if client.is_err() {
-        log::error!("qdrant {:#?}", client.err());
-        exit(1);
+    // inspect and dry runs only read the docs
+    if args.inspect {
+        let res = inspect_categories(&cfg.spec);
+        if res.is_err() {
+            log::error!("inspect {:#?}", res.err());
+            exit(1);
+        }
+        return Ok(());
+    }
+    if args.dry_run {
+        let summaries = dry_run_categories(&cfg.spec);
+        print_summary(&summaries);
+        if summaries.iter().any(|summary| !summary.is_ok()) {
+            exit(1);
+        }
+        return Ok(());
     }
 
-    log::debug!("qdrant {}:{}", cfg.spec.qdrant_url, cfg.spec.qdrant_port);
-    let mut qclient = VectorDB::new(client.unwrap());
+    // setup the vector store (qdrant or embedded)
+    let store = match new_store(&cfg.spec) {
+        Ok(store) => store,
+        Err(err) => {
+            log::error!("vector store {}", err);
+            exit(1);
+        }
+    };
+    log::info!("vector store {}", store.name());
+
+    if args.rollback {
+        let failed = rollback_categories(store.as_ref(), &cfg.spec).await;
+        if failed > 0 {
+            exit(1);
+        }
+        return Ok(());
+    }
     log::info!("executing embedding workflow");
 
-    let embedding_url = format!(
-        "{}:{}/embedding",
-        cfg.spec.llamacpp_embedding_url, cfg.spec.llamacpp_embedding_port
-    );
-
-    if !chat_client {
-        let now = Instant::now();
-        // check our kb docs folder
-        let folder = &format!(
-            "{}/{}",
-            cfg.spec.kb_docs_path.clone(),
-            cfg.spec.category.clone()
-        );
-        let files = load_files_from_dir(
-            folder.into(),
-            &cfg.spec.file_extension,
-            &".".into(),
-            cfg.spec.use_headers,
-            cfg.spec.header_regex,
+    // embedding cache
+    let cache_path = cfg
+        .spec
+        .embedding_cache_path
+        .clone()
+        .unwrap_or(DEFAULT_EMBEDDING_CACHE_PATH.to_string());
+    if args.cache_stats || args.cache_prune {
+        let res = print_cache_stats(
+            &cache_path,
+            &cfg.spec.embedding_model,
+            args.cache_prune,
+            cfg.spec.cache_max_age_days,
         );
-
-        log::debug!("markdown batch {:?}", files.as_ref().unwrap());
-
-        let category = cfg.spec.category.clone();
-        let result = qclient.reset_collection(category.clone()).await;
-        if result.is_err() {
-            log::error!("qdrant reset collection {:#?}", result.err());
+        if res.is_err() {
+            log::error!("embedding cache {:#?}", res.err());
             exit(1);
         }
-
-        for mkd in files.as_ref().unwrap().into_iter() {
-            let mut contents = String::new();
-            if cfg.spec.use_headers {
-                contents.push_str(&mkd.headers.as_ref().unwrap().clone());
-                log::info!("markdown headers {:?}", contents);
-            } else {
-                contents.push_str(&mkd.contents.clone());
-                log::debug!("markdown contents {}", contents);
+        return Ok(());
+    }
+    let cache = if args.no_cache {
+        None
+    } else {
+        match EmbeddingCache::open(&cache_path) {
+            Ok(cache) => Some(cache),
+            Err(err) => {
+                log::error!("embedding cache {:#?}", err);
+                exit(1);
             }
-            let res_embeddings = get_embeddings(embedding_url.clone(), contents.clone()).await;
-            log::debug!("res embeddings {:?}", res_embeddings);
-            let qdrant_res = qclient
-                .upsert_embedding(category.clone(), res_embeddings.unwrap(), &mkd)
-                .await;
-            log::debug!("qdrant upsert results {:?}", qdrant_res);
         }
-        let elapsed = now.elapsed();
-        log::info!("indexed {} files", files.unwrap().len());
-        log::info!("time to complete indexing : {:.2?}", elapsed);
+    };
+    let provider = match new_provider(&cfg.spec) {
+        Ok(provider) => provider,
+        Err(err) => {
+            log::error!("embedding provider {}", err);
+            exit(1);
+        }
+    };
+    log::info!("embedding provider {}", provider.name());
+    let embedder = Arc::new(Embedder::new(
+        provider,
+        cfg.spec.embedding_model.clone(),
+        cache,
+        cfg.spec
+            .embedding_batch_size
+            .unwrap_or(DEFAULT_EMBEDDING_BATCH_SIZE),
+        EmbeddingOptions::from_spec(&cfg.spec),
+    ));
+
+    if args.watch {
+        let res = watch_categories(store.as_ref(), &cfg.spec, embedder).await;
+        if res.is_err() {
+            log::error!("watch {:#?}", res.err());
+            exit(1);
+        }
+    } else if !chat_client {
+        let mode = IndexMode {
+            incremental: args.incremental,
+            resume: args.resume,
+        };
+        let summaries = index_categories(store.as_ref(), &cfg.spec, embedder, mode).await;
+        print_summary(&summaries);
+        if summaries.iter().any(|summary| !summary.is_ok()) {
+            exit(1);
+        }
     } else {
         // chat mode
 
@@ -124,14 +173,27 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
         // create chat session
         let mut session = ChatSession::new(
-            qclient,
+            store,
             openai_client,
             model,
-            embedding_url,
+            embedder,
             cfg.spec.category.clone(),
             cfg.spec.search_limit,
-            cfg.spec.score_threshold,
-        );
+            // fused hybrid scores are on their own scale
+            HybridOptions::from_spec(&cfg.spec)
+                .map_or(cfg.spec.score_threshold, |hybrid| hybrid.score_threshold),
+        )
+        // the dimension is probed when the chat starts
+        .with_metadata_check(
+            CollectionMetadata::from_spec(&cfg.spec, 0),
+            cfg.spec.warn_on_mismatch(),
+        )
+        .with_distance(
+            &cfg.spec.distance.clone().unwrap_or_default(),
+            cfg.spec.hybrid_search.unwrap_or(false),
+        )
+        .with_hnsw_ef(cfg.spec.hnsw_ef)
+        .with_filter(cfg.spec.search_filter.clone().unwrap_or_default());
 
         // build system prompt with tool info
         let system_prompt = "you are an assistant, use the context to help the user:\n".to_string();
@@ -0,0 +1,104 @@
+// Fields read from the yaml style front matter at the top of a file, between
+// two "---" lines. Only the fields used by search filters are read, tags as a
+// [a, b] list, a comma separated string or a "- a" block list and the date
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrontMatter {
+    pub tags: Vec<String>,
+    // seconds since epoch (utc midnight)
+    pub date: Option<u64>,
+}
+
+const DELIMITER: &str = "---";
+
+pub fn parse_front_matter(contents: &str) -> FrontMatter {
+    let mut result = FrontMatter::default();
+    let mut lines = contents.lines();
+    if lines.next().map(str::trim_end) != Some(DELIMITER) {
+        return result;
+    }
+    let mut in_tags = false;
+    for line in lines {
+        if line.trim_end() == DELIMITER {
+            break;
+        }
+        // "- tag" lines following "tags:"
+        if in_tags {
+            if let Some(tag) = line.trim_start().strip_prefix("- ") {
+                push_tags(&mut result.tags, tag);
+                continue;
+            }
+            in_tags = false;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match key.trim() {
+            "tags" if value.is_empty() => in_tags = true,
+            "tags" => push_tags(&mut result.tags, value),
+            "date" => result.date = parse_date(unquote(value)),
+            _ => {}
+        }
+    }
+    result
+}
+
+fn unquote(value: &str) -> &str {
+    value.trim().trim_matches(|c| c == '"' || c == '\'')
+}
+
+fn push_tags(tags: &mut Vec<String>, value: &str) {
+    let value = value.trim().trim_start_matches('[').trim_end_matches(']');
+    for tag in value.split(',').map(unquote) {
+        if !tag.is_empty() && !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+}
+
+// Seconds since epoch of a "YYYY-MM-DD" date, anything after the date (a
+// time) is ignored
+pub fn parse_date(value: &str) -> Option<u64> {
+    let date = value.get(..10)?;
+    let mut parts = date.split('-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || year < 1970 {
+        return None;
+    }
+    // days from civil (proleptic gregorian calendar)
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    Some(days as u64 * 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn front_matter_pass() {
+        let contents = "---\ntitle: Deploy\ntags: [ocp, \"mirror\"]\ndate: 2024-05-01T10:00:00Z\n---\n# Deploy\ntags: not front matter\n";
+        let front = parse_front_matter(contents);
+        assert_eq!(front.tags, vec!["ocp", "mirror"]);
+        assert_eq!(front.date, Some(1714521600));
+
+        let front = parse_front_matter("---\ntags:\n  - ocp\n  - k8s\ndate: '1970-01-02'\n---\n");
+        assert_eq!(front.tags, vec!["ocp", "k8s"]);
+        assert_eq!(front.date, Some(86400));
+
+        assert_eq!(
+            parse_front_matter("# no front matter\n---\n"),
+            FrontMatter::default()
+        );
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("2000-03-01"), Some(951868800));
+    }
+}
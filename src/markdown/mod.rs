@@ -1 +1,2 @@
+pub mod frontmatter;
 pub mod process;
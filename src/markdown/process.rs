@@ -1,14 +1,108 @@
+use crate::api::schema::{ChunkStrategy, Spec};
+use crate::markdown::frontmatter::parse_front_matter;
 use custom_logger as log;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use regex::{Regex, RegexBuilder};
+use sha2::{Digest, Sha256};
 use std::{
+    collections::{BTreeMap, HashMap},
     fs,
     path::{Path, PathBuf},
+    time::UNIX_EPOCH,
 };
 
-#[derive(Clone, Debug)]
+// default chunk size and overlap (in tokens)
+pub const DEFAULT_CHUNK_SIZE: usize = 200;
+pub const DEFAULT_CHUNK_OVERLAP: usize = 20;
+// rough number of words per 100 tokens for english text
+pub const WORDS_PER_100_TOKENS: usize = 75;
+// gitignore style file listing paths that should not be indexed
+const RAG_IGNORE: &str = ".ragignore";
+
+#[derive(Clone, Debug, Default)]
 pub struct MarkdownFile {
     pub path: String,
     pub contents: String,
     pub headers: Option<String>,
+    // ordinal of this chunk within the source file
+    pub chunk: usize,
+    // heading hierarchy of the section, i.e. "Deploy > Rollback > Steps"
+    pub breadcrumb: Option<String>,
+    // named capture groups from headerRegex
+    pub fields: BTreeMap<String, String>,
+    // strategy used to produce this chunk
+    pub strategy: ChunkStrategy,
+    // source file (relative to the prefix) this chunk was read from
+    pub file: String,
+    // sha256 of the full source file contents
+    pub hash: String,
+    // source file modification time (seconds since epoch)
+    pub mtime: u64,
+    // front matter tags of the source file
+    pub tags: Vec<String>,
+    // front matter date of the source file, the modification time without one
+    pub date: u64,
+}
+
+impl MarkdownFile {
+    // the text that gets embedded for this chunk
+    pub fn embedding_text(&self) -> String {
+        match self.strategy {
+            ChunkStrategy::Headers => self.headers.clone().unwrap_or_default(),
+            _ => self.contents.clone(),
+        }
+    }
+}
+
+// Header extraction using headerRegex, the lines spanned by the first match
+// become the header and named capture groups are returned as payload fields
+pub struct HeaderMatcher {
+    regex: Regex,
+    // extend the match to the surrounding "#" comment block
+    block: bool,
+}
+
+impl HeaderMatcher {
+    pub fn new(
+        header_regex: Option<String>,
+        block: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let pattern = header_regex.unwrap_or("# script".to_string());
+        let regex = RegexBuilder::new(&pattern).multi_line(true).build()?;
+        Ok(Self { regex, block })
+    }
+
+    pub fn extract(&self, contents: &str) -> (String, BTreeMap<String, String>) {
+        let mut fields = BTreeMap::new();
+        let Some(caps) = self.regex.captures(contents) else {
+            return (String::new(), fields);
+        };
+        for name in self.regex.capture_names().flatten() {
+            if let Some(value) = caps.name(name) {
+                fields.insert(name.to_string(), value.as_str().trim().to_string());
+            }
+        }
+
+        // extend the match to whole lines
+        let found = caps.get(0).expect("capture group 0 is always set");
+        let lines: Vec<&str> = contents.split('\n').collect();
+        let mut start = contents[..found.start()].matches('\n').count();
+        let mut end = contents[..found.end()].matches('\n').count();
+        if found.end() > found.start() && contents[..found.end()].ends_with('\n') {
+            end -= 1;
+        }
+        if self.block {
+            let is_comment = |line: &str| line.starts_with('#') && !line.starts_with("#!");
+            while start > 0 && is_comment(lines[start - 1]) {
+                start -= 1;
+            }
+            while end + 1 < lines.len() && is_comment(lines[end + 1]) {
+                end += 1;
+            }
+        }
+        (format!("{}\n", lines[start..=end].join("\n")), fields)
+    }
 }
 
 trait HasFileExt {
@@ -24,107 +118,420 @@ impl HasFileExt for Path {
     }
 }
 
-// Load files from directory by ending
+// Chunking settings used when loading files
+pub struct ChunkOptions {
+    pub strategy: ChunkStrategy,
+    // per file extension (i.e. ".md") strategy overrides
+    pub strategies: HashMap<String, ChunkStrategy>,
+    pub header: HeaderMatcher,
+    // chunk size and overlap in tokens
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+}
+
+impl ChunkOptions {
+    pub fn from_spec(spec: &Spec) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            strategy: spec.strategy(),
+            strategies: spec.chunk_strategies.clone().unwrap_or_default(),
+            header: HeaderMatcher::new(
+                spec.header_regex.clone(),
+                spec.header_block.unwrap_or(false),
+            )?,
+            chunk_size: spec.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+            chunk_overlap: spec.chunk_overlap.unwrap_or(DEFAULT_CHUNK_OVERLAP),
+        })
+    }
+
+    // strategy for a file, based on its extension. When several endings
+    // match (i.e. ".md" and ".tmpl.md") the longest one wins
+    pub fn strategy_for(&self, path: &Path) -> ChunkStrategy {
+        self.strategies
+            .iter()
+            .filter(|(ending, _)| path.has_file_extension(ending))
+            .max_by_key(|(ending, _)| ending.len())
+            .map(|(_, strategy)| strategy.clone())
+            .unwrap_or(self.strategy.clone())
+    }
+
+    // word window for the words strategy, sized so that most windows stay
+    // within chunk_size tokens (the indexer splits the ones that don't)
+    pub fn word_window(&self) -> (usize, usize) {
+        (
+            self.chunk_size * WORDS_PER_100_TOKENS / 100,
+            self.chunk_overlap * WORDS_PER_100_TOKENS / 100,
+        )
+    }
+}
+
+// Selects the files to index using include and exclude globs, matched
+// against the path relative to the docs folder
+pub struct FileSelector {
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl FileSelector {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, Box<dyn std::error::Error>> {
+        let build = |patterns: &[String]| -> Result<GlobSet, Box<dyn std::error::Error>> {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns.iter() {
+                builder.add(Glob::new(pattern)?);
+            }
+            Ok(builder.build()?)
+        };
+        Ok(Self {
+            include: build(include)?,
+            exclude: build(exclude)?,
+        })
+    }
+
+    // includes default to every file ending with fileExtension
+    pub fn from_spec(spec: &Spec) -> Result<Self, Box<dyn std::error::Error>> {
+        let include = spec
+            .include
+            .clone()
+            .unwrap_or(vec![format!("**/*{}", spec.file_extension)]);
+        Self::new(&include, &spec.exclude.clone().unwrap_or_default())
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.include.is_match(path) && !self.exclude.is_match(path)
+    }
+}
+
+// Load the selected files from the directory (and sub directories), paths
+// listed in a .ragignore file (gitignore syntax) are skipped
 pub fn load_files_from_dir(
     dir: PathBuf,
-    ending: &str,
-    prefix: &PathBuf,
-    use_headers: bool,
-    header_regex: Option<String>,
+    prefix: &Path,
+    selector: &FileSelector,
+    options: &ChunkOptions,
 ) -> Result<Vec<MarkdownFile>, Box<dyn std::error::Error>> {
     let mut files = Vec::new();
-    for entry in fs::read_dir(dir)? {
-        let path = entry?.path();
-        if path.is_dir() {
-            let mut sub_files =
-                load_files_from_dir(path, ending, prefix, use_headers, header_regex.clone())?;
-            files.append(&mut sub_files);
-        } else if path.is_file() && path.has_file_extension(ending) {
-            log::debug!("reading file {:?} for embedding", path);
-            let contents = fs::read_to_string(&path)?;
-            let path = Path::new(&path).strip_prefix(prefix)?.to_owned();
-            let path_id = path.to_str().expect("path should be valid");
-            if !use_headers {
-                let words: Vec<String> = contents.split_whitespace().map(str::to_string).collect();
-                let res = batch_file_contents(words, path_id.to_string());
-                files.append(&mut res.unwrap());
-            } else {
-                let res = batch_file_headers(contents, path_id.to_string(), header_regex.clone());
-                files.append(&mut res.unwrap());
-            }
+    for path in list_files(&dir, selector)?.iter() {
+        files.append(&mut load_file(path, prefix, options)?);
+    }
+    Ok(files)
+}
+
+// Paths of the selected files in the directory (and sub directories), sorted
+// by name, nothing is read
+pub fn list_files(
+    dir: &Path,
+    selector: &FileSelector,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    let walker = WalkBuilder::new(dir)
+        .standard_filters(false)
+        .add_custom_ignore_filename(RAG_IGNORE)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    for entry in walker {
+        let path = entry?.into_path();
+        if !path.is_file() || path.file_name().is_some_and(|name| name == RAG_IGNORE) {
+            continue;
+        }
+        if !selector.is_match(path.strip_prefix(dir)?) {
+            log::trace!("skipping file {:?}", path);
+            continue;
         }
+        files.push(path);
     }
     Ok(files)
 }
 
+// Source file id stored with the chunks, the path relative to the prefix
+pub fn file_id(path: &Path, prefix: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let path = path.strip_prefix(prefix)?;
+    Ok(path.to_str().expect("path should be valid").to_string())
+}
+
+// Read and chunk one file
+pub fn load_file(
+    path: &Path,
+    prefix: &Path,
+    options: &ChunkOptions,
+) -> Result<Vec<MarkdownFile>, Box<dyn std::error::Error>> {
+    log::debug!("reading file {:?} for embedding", path);
+    let contents = fs::read_to_string(path)?;
+    let mtime = fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_secs();
+    let hash = content_hash(&contents);
+    let front = parse_front_matter(&contents);
+    let strategy = options.strategy_for(path);
+    let path_id = file_id(path, prefix)?;
+    let mut res = match strategy {
+        ChunkStrategy::Words => {
+            let words: Vec<String> = contents.split_whitespace().map(str::to_string).collect();
+            let (size, overlap) = options.word_window();
+            batch_file_contents(words, path_id.clone(), size, overlap)?
+        }
+        ChunkStrategy::Headers => batch_file_headers(contents, path_id.clone(), &options.header)?,
+        ChunkStrategy::Sections => batch_file_sections(contents, path_id.clone())?,
+    };
+    for mkd in res.iter_mut() {
+        mkd.file = path_id.clone();
+        mkd.hash = hash.clone();
+        mkd.mtime = mtime;
+        mkd.tags = front.tags.clone();
+        mkd.date = front.date.unwrap_or(mtime);
+        mkd.strategy = strategy.clone();
+    }
+    Ok(res)
+}
+
 pub fn batch_file_contents(
     words: Vec<String>,
     path_id: String,
+    batch_size: usize,
+    overlap: usize,
 ) -> Result<Vec<MarkdownFile>, Box<dyn std::error::Error>> {
     let mut result: Vec<MarkdownFile> = Vec::new();
-    let batch_size = 200;
-    let overlap = 20;
-    let batch_count = words.len() / batch_size;
-    let remainder = words.len() % batch_size;
-
-    log::info!("batch count {}", batch_count);
-    log::info!("remainder  {}", remainder);
-    log::info!("path id    {}", path_id);
-
-    for i in 0..batch_count {
-        let from = i * batch_size;
-        let to = (i * batch_size) + batch_size + overlap;
-        log::info!("from {}", from);
-        log::info!("to   {}", to);
+
+    log::debug!("word count {}", words.len());
+    log::debug!("path id    {}", path_id);
+
+    for (i, (from, to)) in windows(words.len(), batch_size, overlap)
+        .into_iter()
+        .enumerate()
+    {
+        log::debug!("from {} to {}", from, to);
         let mkd = MarkdownFile {
             path: format!("{}-{}", path_id, i),
             headers: None,
-            contents: words[from..to].join(" ").clone(),
+            chunk: i,
+            contents: words[from..to].join(" "),
+            ..Default::default()
         };
-        log::info!("content length  {}", mkd.contents.len());
-        result.insert(0, mkd);
+        log::debug!("content length  {}", mkd.contents.len());
+        result.push(mkd);
     }
 
-    let mkd = MarkdownFile {
-        path: format!("{}-{}", path_id, batch_count),
-        headers: None,
-        contents: words[batch_count * batch_size..].join(" ").clone(),
-    };
-    log::info!("remainder from {}", batch_count * batch_size);
-    log::info!("remainder length {}", mkd.contents.len());
-    result.insert(0, mkd);
-
     Ok(result)
 }
 
+// Split 0..len into [from, to) windows of size, each window starts overlap
+// before the end of the previous one. Always returns at least one window
+pub fn windows(len: usize, size: usize, overlap: usize) -> Vec<(usize, usize)> {
+    let size = size.max(1);
+    let step = size.saturating_sub(overlap).max(1);
+    let mut result = Vec::new();
+    let mut from = 0;
+    loop {
+        let to = (from + size).min(len);
+        result.push((from, to));
+        if to >= len {
+            break;
+        }
+        from += step;
+    }
+    result
+}
+
 pub fn batch_file_headers(
     words: String,
     path_id: String,
-    header_regex: Option<String>,
+    header: &HeaderMatcher,
 ) -> Result<Vec<MarkdownFile>, Box<dyn std::error::Error>> {
     let mut result: Vec<MarkdownFile> = Vec::new();
-    let mut headers = String::new();
 
     log::debug!("path id    {}", path_id);
 
-    let lines: Vec<String> = words.split("\n").map(str::to_string).collect();
-    for line in lines.iter() {
-        let header_text = header_regex.as_ref().map_or("# script", |v| v).to_string();
-        if line.contains(&header_text.clone()) {
-            headers.push_str(&format!("{}\n", line));
-            break;
-        }
+    let (headers, fields) = header.extract(&words);
+    if headers.is_empty() {
+        log::warn!("no header found in {}", path_id);
     }
     let mkd = MarkdownFile {
-        path: format!("{}", path_id),
+        path: path_id.to_string(),
         headers: Some(headers),
         contents: words.clone(),
+        fields,
+        ..Default::default()
     };
-    if mkd.headers.is_some() {
-        log::debug!("headers length  {}", mkd.headers.as_ref().unwrap().len());
+    if let Some(headers) = mkd.headers.as_ref() {
+        log::debug!("headers length  {}", headers.len());
     }
     log::debug!("content length  {}", mkd.contents.len());
     result.insert(0, mkd);
 
     Ok(result)
 }
+
+// Split markdown on its #, ## and ### headings, each section becomes a chunk
+// prefixed with its heading breadcrumb (i.e. "Deploy > Rollback > Steps")
+pub fn batch_file_sections(
+    contents: String,
+    path_id: String,
+) -> Result<Vec<MarkdownFile>, Box<dyn std::error::Error>> {
+    let mut result: Vec<MarkdownFile> = Vec::new();
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    let mut body: Vec<&str> = Vec::new();
+    let mut in_code = false;
+
+    log::debug!("path id    {}", path_id);
+
+    let mut push_section = |headings: &Vec<(usize, String)>, body: &Vec<&str>| {
+        let text = body.join("\n").trim().to_string();
+        if text.is_empty() {
+            return;
+        }
+        let breadcrumb = headings
+            .iter()
+            .map(|(_, title)| title.clone())
+            .collect::<Vec<String>>()
+            .join(" > ");
+        let chunk = result.len();
+        let mkd = MarkdownFile {
+            path: format!("{}-{}", path_id, chunk),
+            headers: None,
+            chunk,
+            contents: if breadcrumb.is_empty() {
+                text
+            } else {
+                format!("{}\n\n{}", breadcrumb, text)
+            },
+            breadcrumb: (!breadcrumb.is_empty()).then_some(breadcrumb),
+            ..Default::default()
+        };
+        log::debug!("section {:?} length {}", mkd.breadcrumb, mkd.contents.len());
+        result.push(mkd);
+    };
+
+    for line in contents.lines() {
+        // headings are not recognised inside fenced code blocks
+        if line.trim_start().starts_with("```") {
+            in_code = !in_code;
+        }
+        let level = line.chars().take_while(|c| *c == '#').count();
+        let is_heading = !in_code
+            && (1..=3).contains(&level)
+            && line[level..].starts_with(' ')
+            && !line[level..].trim().is_empty();
+        if is_heading {
+            push_section(&headings, &body);
+            body.clear();
+            headings.retain(|(l, _)| *l < level);
+            headings.push((level, line[level..].trim().to_string()));
+        } else {
+            body.push(line);
+        }
+    }
+    push_section(&headings, &body);
+
+    Ok(result)
+}
+
+// Hex encoded sha256 of the given contents
+pub fn content_hash(contents: &str) -> String {
+    format!("{:x}", Sha256::digest(contents.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn file_selector_pass() {
+        let selector = FileSelector::new(
+            &["**/*.md".to_string(), "**/*.sh".to_string()],
+            &["vendor/**".to_string(), "**/generated/**".to_string()],
+        )
+        .unwrap();
+        assert!(selector.is_match(Path::new("deploy.md")));
+        assert!(selector.is_match(Path::new("ocp/mirror.sh")));
+        assert!(!selector.is_match(Path::new("ocp/values.yaml")));
+        assert!(!selector.is_match(Path::new("vendor/lib/readme.md")));
+        assert!(!selector.is_match(Path::new("ocp/generated/api.md")));
+
+        let options = ChunkOptions {
+            strategy: ChunkStrategy::Words,
+            strategies: HashMap::from([
+                (".md".to_string(), ChunkStrategy::Sections),
+                (".tmpl.md".to_string(), ChunkStrategy::Headers),
+            ]),
+            header: HeaderMatcher::new(None, false).unwrap(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            chunk_overlap: DEFAULT_CHUNK_OVERLAP,
+        };
+        assert_eq!(
+            options.strategy_for(Path::new("deploy.md")),
+            ChunkStrategy::Sections
+        );
+        assert_eq!(
+            options.strategy_for(Path::new("deploy.tmpl.md")),
+            ChunkStrategy::Headers
+        );
+        assert_eq!(
+            options.strategy_for(Path::new("notes.txt")),
+            ChunkStrategy::Words
+        );
+    }
+
+    #[test]
+    fn windows_pass() {
+        assert_eq!(windows(0, 200, 20), vec![(0, 0)]);
+        assert_eq!(windows(150, 200, 20), vec![(0, 150)]);
+        assert_eq!(
+            windows(400, 200, 20),
+            vec![(0, 200), (180, 380), (360, 400)]
+        );
+        // overlap larger than the window still makes progress
+        assert_eq!(windows(3, 1, 5), vec![(0, 1), (1, 2), (2, 3)]);
+    }
+
+    #[test]
+    fn batch_file_contents_pass() {
+        let words: Vec<String> = (0..25).map(|i| i.to_string()).collect();
+        let res = batch_file_contents(words, "notes.txt".to_string(), 10, 2).unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[1].path, "notes.txt-1");
+        assert!(res[1].contents.starts_with("8 9 10"));
+        assert!(res[2].contents.ends_with("24"));
+    }
+
+    #[test]
+    fn batch_file_sections_pass() {
+        let contents = "intro text\n# Deploy\n## Rollback\n### Steps\nrun the script\n```\n# not a heading\n```\n## Verify\ncheck pods\n".to_string();
+        let res = batch_file_sections(contents, "docs/deploy.md".to_string()).unwrap();
+        assert_eq!(res.len(), 3);
+        assert_eq!(res[0].breadcrumb, None);
+        assert_eq!(res[0].contents, "intro text");
+        assert_eq!(
+            res[1].breadcrumb.as_deref(),
+            Some("Deploy > Rollback > Steps")
+        );
+        assert!(res[1]
+            .contents
+            .starts_with("Deploy > Rollback > Steps\n\nrun the script"));
+        assert!(res[1].contents.contains("# not a heading"));
+        assert_eq!(res[2].breadcrumb.as_deref(), Some("Deploy > Verify"));
+        assert_eq!(res[2].path, "docs/deploy.md-2");
+        assert_eq!(res[2].chunk, 2);
+    }
+
+    #[test]
+    fn batch_file_headers_pass() {
+        let contents = "#!/bin/bash\n# This script mirrors a release\n# usage: mirror.sh <version>\n\necho done\n".to_string();
+
+        let header =
+            HeaderMatcher::new(Some("^# This script (?P<summary>.*)$".to_string()), false).unwrap();
+        let res = batch_file_headers(contents.clone(), "mirror.sh".to_string(), &header).unwrap();
+        assert_eq!(
+            res[0].headers.as_deref(),
+            Some("# This script mirrors a release\n")
+        );
+        assert_eq!(res[0].fields["summary"], "mirrors a release");
+
+        let header = HeaderMatcher::new(Some("# This script".to_string()), true).unwrap();
+        let res = batch_file_headers(contents, "mirror.sh".to_string(), &header).unwrap();
+        assert_eq!(
+            res[0].headers.as_deref(),
+            Some("# This script mirrors a release\n# usage: mirror.sh <version>\n")
+        );
+        assert!(res[0].fields.is_empty());
+    }
+}
@@ -1,96 +1,555 @@
 use crate::error::handler::*;
-use crate::MarkdownFile;
+use crate::qdrant::hybrid::{
+    bm25_query, rrf_fuse, HybridOptions, DENSE_VECTOR, PREFETCH_FACTOR, SPARSE_VECTOR,
+};
+use crate::qdrant::metadata::CollectionMetadata;
+use crate::qdrant::params::{search_params, IndexParams};
+use crate::store::filter::SearchFilter;
+use crate::store::vector::{PointPayload, SearchHit, SearchQuery, StorePoint, VectorStore};
+use async_trait::async_trait;
+use custom_logger as log;
+use qdrant_client::qdrant::point_id::PointIdOptions;
 use qdrant_client::qdrant::vectors_config::Config;
 use qdrant_client::qdrant::with_payload_selector::SelectorOptions;
 use qdrant_client::qdrant::{
-    CreateCollection, Distance, PointStruct, ScoredPoint, SearchPoints, UpsertPointsBuilder,
-    VectorParams, VectorsConfig, WithPayloadSelector,
+    Condition, CreateAlias, CreateCollection, CreateFieldIndexCollectionBuilder,
+    DeletePointsBuilder, Distance, FieldType, Filter, GetPointsBuilder, Modifier, NamedVectors,
+    PayloadIncludeSelector, PointId, PointStruct, Range, ScoredPoint, ScrollPointsBuilder,
+    SearchPoints, SparseIndices, SparseVectorConfig, SparseVectorParams, UpsertPointsBuilder,
+    Vector, VectorParams, VectorParamsMap, VectorsConfig, WithPayloadSelector,
 };
 use qdrant_client::Payload;
 use qdrant_client::Qdrant;
-use serde_json::json;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// page size used when scrolling through a collection
+const SCROLL_LIMIT: u32 = 256;
+// holds one point per collection with the settings it was indexed with
+const METADATA_COLLECTION: &str = "rag_collection_metadata";
+// payload indexes created with every collection, the fields search filters use
+const PAYLOAD_INDEXES: [(&str, FieldType); 5] = [
+    ("file", FieldType::Keyword),
+    ("dirs", FieldType::Keyword),
+    ("extension", FieldType::Keyword),
+    ("tags", FieldType::Keyword),
+    ("date", FieldType::Integer),
+];
 
 pub struct VectorDB {
-    id: u64,
     client: Qdrant,
+    // set when collections hold a dense and a sparse (bm25) vector
+    hybrid: Option<HybridOptions>,
+    params: IndexParams,
+}
+
+// qdrant point for a store point, named dense + sparse vectors when the
+// point has a sparse vector
+fn to_qdrant_point(point: StorePoint) -> Result<PointStruct, Box<dyn std::error::Error>> {
+    let payload: Payload =
+        Value::Object(point.payload)
+            .try_into()
+            .map_err(|_| EmbeddingsError {
+                details: format!("invalid payload for point {}", point.id),
+            })?;
+    let Some(sparse) = point.sparse else {
+        return Ok(PointStruct::new(point.id, point.vector, payload));
+    };
+    let vectors = NamedVectors::default()
+        .add_vector(DENSE_VECTOR, Vector::new_dense(point.vector))
+        .add_vector(
+            SPARSE_VECTOR,
+            Vector::new_sparse(sparse.indices, sparse.values),
+        );
+    Ok(PointStruct::new(point.id, vectors, payload))
+}
+
+fn to_hit(point: ScoredPoint) -> SearchHit {
+    let id = match point.id.and_then(|id| id.point_id_options) {
+        Some(PointIdOptions::Uuid(id)) => id,
+        Some(PointIdOptions::Num(id)) => id.to_string(),
+        None => String::new(),
+    };
+    SearchHit {
+        id,
+        score: point.score,
+        payload: to_json(point.payload),
+    }
+}
+
+fn to_json(payload: HashMap<String, qdrant_client::qdrant::Value>) -> PointPayload {
+    payload
+        .into_iter()
+        .map(|(key, value)| (key, value.into_json()))
+        .collect()
+}
+
+// Qdrant filter for a search filter, none when it is empty. A path matches
+// the directories above the file or the file itself
+fn to_filter(filter: &SearchFilter) -> Result<Option<Filter>, Box<dyn std::error::Error>> {
+    let mut conditions = Vec::new();
+    if let Some(path) = filter.trimmed_path() {
+        conditions.push(
+            Filter::should([
+                Condition::matches("dirs", path.clone()),
+                Condition::matches("file", path),
+            ])
+            .into(),
+        );
+    }
+    let extensions = filter.extensions();
+    if !extensions.is_empty() {
+        conditions.push(Condition::matches("extension", extensions));
+    }
+    for tag in filter.tags.iter() {
+        conditions.push(Condition::matches("tags", tag.clone()));
+    }
+    let (after, before) = filter.date_range()?;
+    if after.is_some() || before.is_some() {
+        conditions.push(Condition::range(
+            "date",
+            Range {
+                gte: after.map(|after| after as f64),
+                lte: before.map(|before| before as f64),
+                ..Default::default()
+            },
+        ));
+    }
+    if conditions.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Filter::must(conditions)))
+}
+
+// Metadata point id of a collection
+fn metadata_id(collection: &str) -> String {
+    Uuid::new_v5(
+        &Uuid::NAMESPACE_URL,
+        format!("metadata#{}", collection).as_bytes(),
+    )
+    .to_string()
 }
 
 impl VectorDB {
     pub fn new(client: Qdrant) -> Self {
-        Self { id: 0, client }
+        Self {
+            client,
+            hybrid: None,
+            params: IndexParams::default(),
+        }
+    }
+
+    pub fn with_hybrid(mut self, hybrid: Option<HybridOptions>) -> Self {
+        self.hybrid = hybrid;
+        self
+    }
+
+    pub fn with_params(mut self, params: IndexParams) -> Self {
+        self.params = params;
+        self
     }
 
-    pub async fn reset_collection(
+    // the collection behind an alias, other names are returned as is
+    async fn resolve(&self, name: String) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(self.alias_target(name.clone()).await?.unwrap_or(name))
+    }
+
+    async fn create_collection(
         &self,
         collection: String,
+        size: u64,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        self.client.delete_collection(collection.clone()).await?;
-
+        log::info!(
+            "creating collection {} (vector size {}, distance {:?})",
+            collection,
+            size,
+            self.params.distance
+        );
+        let params = self.params.vector_params(size);
+        let (config, sparse_vectors_config) = match self.hybrid {
+            None => (Config::Params(params), None),
+            Some(_) => {
+                let dense = HashMap::from([(DENSE_VECTOR.to_string(), params)]);
+                let sparse = HashMap::from([(
+                    SPARSE_VECTOR.to_string(),
+                    SparseVectorParams {
+                        index: None,
+                        modifier: Some(Modifier::Idf.into()),
+                    },
+                )]);
+                (
+                    Config::ParamsMap(VectorParamsMap { map: dense }),
+                    Some(SparseVectorConfig { map: sparse }),
+                )
+            }
+        };
         self.client
             .create_collection(CreateCollection {
-                collection_name: collection,
+                collection_name: collection.clone(),
                 vectors_config: Some(VectorsConfig {
-                    config: Some(Config::Params(VectorParams {
-                        size: 384,
-                        distance: Distance::Cosine.into(),
-                        hnsw_config: None,
-                        quantization_config: None,
-                        on_disk: None,
-                        datatype: None,
-                        multivector_config: None,
-                    })),
+                    config: Some(config),
                 }),
+                sparse_vectors_config,
                 ..Default::default()
             })
             .await?;
 
+        for (field, field_type) in PAYLOAD_INDEXES {
+            self.client
+                .create_field_index(
+                    CreateFieldIndexCollectionBuilder::new(collection.clone(), field, field_type)
+                        .wait(true),
+                )
+                .await?;
+        }
         Ok(())
     }
+}
+
+#[async_trait]
+impl VectorStore for VectorDB {
+    fn name(&self) -> &str {
+        "qdrant"
+    }
+
+    fn hybrid(&self) -> Option<&HybridOptions> {
+        self.hybrid.as_ref()
+    }
 
-    pub async fn upsert_embedding(
-        &mut self,
+    async fn reset_collection(
+        &self,
         collection: String,
-        embedding: Vec<f32>,
-        mkd_file: &MarkdownFile,
+        metadata: &CollectionMetadata,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let payload: Payload = json!({
-            "id": mkd_file.path.clone(),
-            "contents": mkd_file.contents.clone(),
-        })
-        .try_into()
-        .map_err(|_| EmbeddingsError {
-            details: "".to_string(),
+        self.client.delete_collection(collection.clone()).await?;
+        self.create_collection(collection.clone(), metadata.dimension)
+            .await?;
+        self.write_metadata(collection, metadata).await
+    }
+
+    async fn ensure_collection(
+        &self,
+        collection: String,
+        metadata: &CollectionMetadata,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self.resolve(collection).await?;
+        if self.client.collection_exists(collection.clone()).await? {
+            return self.check_vector_size(collection, metadata.dimension).await;
+        }
+        self.create_collection(collection.clone(), metadata.dimension)
+            .await?;
+        self.write_metadata(collection, metadata).await
+    }
+
+    async fn read_metadata(
+        &self,
+        collection: String,
+    ) -> Result<Option<CollectionMetadata>, Box<dyn std::error::Error>> {
+        if !self.client.collection_exists(METADATA_COLLECTION).await? {
+            return Ok(None);
+        }
+        // metadata is stored under the collection name, not the alias
+        let collection = self.resolve(collection).await?;
+        let response = self
+            .client
+            .get_points(
+                GetPointsBuilder::new(METADATA_COLLECTION, vec![metadata_id(&collection).into()])
+                    .with_payload(true),
+            )
+            .await?;
+        let metadata = response
+            .result
+            .first()
+            .and_then(|point| point.payload.get("metadata"))
+            .and_then(|value| value.as_str());
+        match metadata {
+            Some(metadata) => Ok(Some(serde_json::from_str(metadata)?)),
+            None => Ok(None),
+        }
+    }
+
+    // store the settings a collection was indexed with, qdrant-client 1.10
+    // has no collection level metadata so a small side collection is used
+    async fn write_metadata(
+        &self,
+        collection: String,
+        metadata: &CollectionMetadata,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.client.collection_exists(METADATA_COLLECTION).await? {
+            self.client
+                .create_collection(CreateCollection {
+                    collection_name: METADATA_COLLECTION.to_string(),
+                    vectors_config: Some(VectorsConfig {
+                        config: Some(Config::Params(VectorParams {
+                            size: 1,
+                            distance: Distance::Dot.into(),
+                            ..Default::default()
+                        })),
+                    }),
+                    ..Default::default()
+                })
+                .await?;
+        }
+        let value = json!({
+            "collection": collection.clone(),
+            "metadata": serde_json::to_string(metadata)?,
+        });
+        let payload: Payload = value.try_into().map_err(|_| EmbeddingsError {
+            details: format!("invalid metadata payload for {}", collection),
         })?;
+        let point = PointStruct::new(metadata_id(&collection), vec![1.0], payload);
+        self.client
+            .upsert_points(UpsertPointsBuilder::new(METADATA_COLLECTION, vec![point]).wait(true))
+            .await?;
+        Ok(())
+    }
 
-        let points = vec![PointStruct::new(self.id, embedding.clone(), payload)];
+    async fn vector_size(&self, collection: String) -> Result<u64, Box<dyn std::error::Error>> {
+        let response = self.client.collection_info(collection.clone()).await?;
+        let config = response
+            .result
+            .and_then(|info| info.config)
+            .and_then(|config| config.params)
+            .and_then(|params| params.vectors_config)
+            .and_then(|vectors| vectors.config);
+        let size = match (config, self.hybrid.is_some()) {
+            (Some(Config::Params(params)), false) => Some(params.size),
+            (Some(Config::ParamsMap(params)), true) => {
+                params.map.get(DENSE_VECTOR).map(|params| params.size)
+            }
+            _ => None,
+        };
+        size.ok_or_else(|| {
+            let expected = if self.hybrid.is_some() {
+                "a named dense vector (hybridSearch is set)"
+            } else {
+                "a single unnamed vector (hybridSearch is not set)"
+            };
+            Box::new(EmbeddingsError::new(&format!(
+                "collection {} doesn't have {}, re-index it",
+                collection, expected
+            ))) as Box<dyn std::error::Error>
+        })
+    }
+
+    // upsert a batch of points and wait for qdrant to acknowledge the write
+    async fn upsert_points(
+        &self,
+        collection: String,
+        points: Vec<StorePoint>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let points = points
+            .into_iter()
+            .map(to_qdrant_point)
+            .collect::<Result<Vec<PointStruct>, _>>()?;
         self.client
-            .upsert_points(UpsertPointsBuilder::new(collection, points))
+            .upsert_points(UpsertPointsBuilder::new(collection, points).wait(true))
             .await?;
-        self.id += 1;
 
         Ok(())
     }
 
-    pub async fn search(
+    async fn delete_files(
+        &self,
+        collection: String,
+        files: Vec<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if files.is_empty() {
+            return Ok(());
+        }
+        self.client
+            .delete_points(
+                DeletePointsBuilder::new(collection)
+                    .points(Filter::should([
+                        Condition::matches("file", files.clone()),
+                        Condition::matches("dirs", files),
+                    ]))
+                    .wait(true),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_stale(
+        &self,
+        collection: String,
+        files: HashMap<String, String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if files.is_empty() {
+            return Ok(());
+        }
+        let conditions: Vec<Condition> = files
+            .into_iter()
+            .map(|(file, hash)| {
+                Filter {
+                    must: vec![Condition::matches("file", file)],
+                    must_not: vec![Condition::matches("hash", hash)],
+                    ..Default::default()
+                }
+                .into()
+            })
+            .collect();
+        self.client
+            .delete_points(
+                DeletePointsBuilder::new(collection)
+                    .points(Filter::should(conditions))
+                    .wait(true),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list_collections(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let response = self.client.list_collections().await?;
+        Ok(response
+            .collections
+            .into_iter()
+            .map(|collection| collection.name)
+            .filter(|name| name != METADATA_COLLECTION)
+            .collect())
+    }
+
+    async fn delete_collection(
         &self,
         collection: String,
-        embedding: Vec<f32>,
-        search_limit: u64,
-    ) -> Result<Vec<ScoredPoint>, Box<dyn std::error::Error>> {
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        log::info!("deleting collection {}", collection);
+        self.client.delete_collection(collection.clone()).await?;
+        if self.client.collection_exists(METADATA_COLLECTION).await? {
+            let id: PointId = metadata_id(&collection).into();
+            self.client
+                .delete_points(
+                    DeletePointsBuilder::new(METADATA_COLLECTION)
+                        .points(vec![id])
+                        .wait(true),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn alias_target(
+        &self,
+        alias: String,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let response = self.client.list_aliases().await?;
+        Ok(response
+            .aliases
+            .into_iter()
+            .find(|description| description.alias_name == alias)
+            .map(|description| description.collection_name))
+    }
+
+    // qdrant re-points an existing alias when it is created again, the
+    // switch is a single alias operation
+    async fn switch_alias(
+        &self,
+        alias: String,
+        collection: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.client
+            .create_alias(CreateAlias {
+                collection_name: collection,
+                alias_name: alias,
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn scroll(
+        &self,
+        collection: String,
+        fields: &[&str],
+    ) -> Result<Vec<PointPayload>, Box<dyn std::error::Error>> {
+        let mut result = Vec::new();
+        let mut offset = None;
+        loop {
+            let mut request = ScrollPointsBuilder::new(collection.clone())
+                .limit(SCROLL_LIMIT)
+                .with_payload(PayloadIncludeSelector {
+                    fields: fields.iter().map(|field| field.to_string()).collect(),
+                });
+            if let Some(id) = offset {
+                request = request.offset(id);
+            }
+            let response = self.client.scroll(request).await?;
+            for point in response.result.into_iter() {
+                result.push(to_json(point.payload));
+            }
+            offset = response.next_page_offset;
+            if offset.is_none() {
+                break;
+            }
+        }
+        Ok(result)
+    }
+
+    // search the collection with the query embedding, hybrid collections are
+    // also searched with the bm25 terms of the query text and both result
+    // lists are fused
+    async fn search(
+        &self,
+        collection: String,
+        query: SearchQuery,
+    ) -> Result<Vec<SearchHit>, Box<dyn std::error::Error>> {
+        let search_limit = query.limit;
+        let filter = to_filter(&query.filter)?;
         let payload_selector = WithPayloadSelector {
             selector_options: Some(SelectorOptions::Enable(true)),
         };
 
-        let search_points = SearchPoints {
-            collection_name: collection,
-            vector: embedding.clone(),
-            limit: search_limit,
-            with_payload: Some(payload_selector),
-            ..Default::default()
+        let Some(hybrid) = self.hybrid.as_ref() else {
+            let search_points = SearchPoints {
+                collection_name: collection,
+                vector: query.embedding,
+                limit: search_limit,
+                params: search_params(query.hnsw_ef),
+                filter,
+                with_payload: Some(payload_selector),
+                ..Default::default()
+            };
+            let search_result = self.client.search_points(search_points).await?;
+            return Ok(search_result.result.into_iter().map(to_hit).collect());
         };
 
-        let search_result = self.client.search_points(search_points).await?;
-        let result = search_result.result.clone();
-        Ok(result)
+        let limit = search_limit * PREFETCH_FACTOR;
+        let dense = SearchPoints {
+            collection_name: collection.clone(),
+            vector: query.embedding,
+            vector_name: Some(DENSE_VECTOR.to_string()),
+            limit,
+            params: search_params(query.hnsw_ef),
+            filter: filter.clone(),
+            with_payload: Some(payload_selector.clone()),
+            ..Default::default()
+        };
+        let dense = self.client.search_points(dense).await?.result;
+        let dense: Vec<SearchHit> = dense.into_iter().map(to_hit).collect();
+        let terms = bm25_query(&query.text);
+        let sparse = if terms.indices.is_empty() {
+            Vec::new()
+        } else {
+            let sparse = SearchPoints {
+                collection_name: collection,
+                vector: terms.values,
+                sparse_indices: Some(SparseIndices {
+                    data: terms.indices,
+                }),
+                vector_name: Some(SPARSE_VECTOR.to_string()),
+                limit,
+                filter,
+                with_payload: Some(payload_selector),
+                ..Default::default()
+            };
+            let sparse = self.client.search_points(sparse).await?.result;
+            sparse.into_iter().map(to_hit).collect()
+        };
+        log::debug!(
+            "hybrid search : {} dense, {} sparse results",
+            dense.len(),
+            sparse.len()
+        );
+        Ok(rrf_fuse(dense, sparse, hybrid, search_limit as usize))
     }
 }
@@ -0,0 +1,204 @@
+use crate::api::schema::Spec;
+use crate::store::vector::SearchHit;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// names of the vectors in a hybrid collection
+pub const DENSE_VECTOR: &str = "dense";
+pub const SPARSE_VECTOR: &str = "sparse";
+
+// defaults when not set in the config
+const DEFAULT_RRF_K: f32 = 60.0;
+const DEFAULT_BM25_AVG_LENGTH: f32 = 256.0;
+// a point ranked first by only one of the two searches scores about 0.5
+const DEFAULT_HYBRID_SCORE_THRESHOLD: f32 = 0.3;
+// bm25 term frequency saturation and length normalization
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
+// each of the two searches returns limit * PREFETCH_FACTOR candidates
+pub const PREFETCH_FACTOR: u64 = 4;
+
+// Settings for collections with a dense and a sparse (bm25) vector, results of
+// the two searches are merged with weighted reciprocal rank fusion
+#[derive(Clone, Debug, PartialEq)]
+pub struct HybridOptions {
+    pub dense_weight: f32,
+    pub sparse_weight: f32,
+    pub rrf_k: f32,
+    // expected document length (in terms) for the bm25 length normalization
+    pub avg_length: f32,
+    // used instead of scoreThreshold for the fused scores
+    pub score_threshold: f32,
+}
+
+impl HybridOptions {
+    // none unless hybridSearch is set
+    pub fn from_spec(spec: &Spec) -> Option<Self> {
+        if !spec.hybrid_search.unwrap_or(false) {
+            return None;
+        }
+        Some(Self {
+            dense_weight: spec.dense_weight.unwrap_or(1.0),
+            sparse_weight: spec.sparse_weight.unwrap_or(1.0),
+            rrf_k: spec.rrf_k.unwrap_or(DEFAULT_RRF_K),
+            avg_length: spec.bm25_avg_length.unwrap_or(DEFAULT_BM25_AVG_LENGTH),
+            score_threshold: spec
+                .hybrid_score_threshold
+                .unwrap_or(DEFAULT_HYBRID_SCORE_THRESHOLD),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SparseVector {
+    pub indices: Vec<u32>,
+    pub values: Vec<f32>,
+}
+
+// Lower case terms, split on anything but letters, digits, '-' and '_' so
+// command names and flags (oc-mirror, --insecure) survive as terms
+pub fn terms(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+        .map(|term| term.trim_matches('-'))
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_string())
+        .collect()
+}
+
+// Stable 32 bit term id (fnv-1a), the same term always maps to the same index
+fn term_index(term: &str) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in term.as_bytes() {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+fn to_sparse(weights: BTreeMap<u32, f32>) -> SparseVector {
+    let (indices, values) = weights.into_iter().unzip();
+    SparseVector { indices, values }
+}
+
+// Document side of bm25 : saturated and length normalized term frequencies,
+// the idf part is applied by the store (idf modifier on the qdrant sparse vector)
+pub fn bm25_document(text: &str, avg_length: f32) -> SparseVector {
+    let terms = terms(text);
+    let length = terms.len() as f32;
+    let mut counts: BTreeMap<u32, f32> = BTreeMap::new();
+    for term in terms.iter() {
+        *counts.entry(term_index(term)).or_default() += 1.0;
+    }
+    let norm = BM25_K1 * (1.0 - BM25_B + BM25_B * length / avg_length.max(1.0));
+    let weights = counts
+        .into_iter()
+        .map(|(index, tf)| (index, tf * (BM25_K1 + 1.0) / (tf + norm)))
+        .collect();
+    to_sparse(weights)
+}
+
+// Query side of bm25 : every distinct term with weight 1
+pub fn bm25_query(text: &str) -> SparseVector {
+    let weights = terms(text)
+        .iter()
+        .map(|term| (term_index(term), 1.0))
+        .collect();
+    to_sparse(weights)
+}
+
+// Weighted reciprocal rank fusion, each list adds weight / (k + rank) for a
+// point. Scores are scaled so a point ranked first in both lists scores 1.0
+pub fn rrf_fuse(
+    dense: Vec<SearchHit>,
+    sparse: Vec<SearchHit>,
+    options: &HybridOptions,
+    limit: usize,
+) -> Vec<SearchHit> {
+    let mut fused: HashMap<String, (f32, SearchHit)> = HashMap::new();
+    for (weight, points) in [
+        (options.dense_weight, dense),
+        (options.sparse_weight, sparse),
+    ] {
+        for (rank, point) in points.into_iter().enumerate() {
+            let score = weight / (options.rrf_k + rank as f32 + 1.0);
+            fused
+                .entry(point.id.clone())
+                .and_modify(|(total, _)| *total += score)
+                .or_insert((score, point));
+        }
+    }
+    let best = (options.dense_weight + options.sparse_weight) / (options.rrf_k + 1.0);
+    let mut result: Vec<SearchHit> = fused
+        .into_values()
+        .map(|(score, mut point)| {
+            point.score = if best > 0.0 { score / best } else { 0.0 };
+            point
+        })
+        .collect();
+    result.sort_by(|a, b| b.score.total_cmp(&a.score));
+    result.truncate(limit);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    fn point(id: u64) -> SearchHit {
+        SearchHit {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn bm25_pass() {
+        assert_eq!(
+            terms("Run `oc adm release mirror --insecure` (oc-mirror)"),
+            vec![
+                "run",
+                "oc",
+                "adm",
+                "release",
+                "mirror",
+                "insecure",
+                "oc-mirror"
+            ]
+        );
+        let doc = bm25_document("mirror the release mirror", 4.0);
+        assert_eq!(doc.indices.len(), 3);
+        let mirror = doc.indices.iter().position(|i| *i == term_index("mirror"));
+        let release = doc.indices.iter().position(|i| *i == term_index("release"));
+        assert!(doc.values[mirror.unwrap()] > doc.values[release.unwrap()]);
+        let query = bm25_query("mirror mirror release");
+        assert_eq!(query.values, vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn rrf_fuse_pass() {
+        let options = HybridOptions {
+            dense_weight: 1.0,
+            sparse_weight: 1.0,
+            rrf_k: 60.0,
+            avg_length: 256.0,
+            score_threshold: DEFAULT_HYBRID_SCORE_THRESHOLD,
+        };
+        let fused = rrf_fuse(
+            vec![point(1), point(2), point(3)],
+            vec![point(3), point(1)],
+            &options,
+            2,
+        );
+        assert_eq!(fused.len(), 2);
+        assert_eq!(fused[0].id, point(1).id);
+        assert_eq!(fused[1].id, point(3).id);
+        assert!(fused[0].score < 1.0 && fused[0].score > fused[1].score);
+
+        // a hit found by only one of the searches passes the default threshold
+        let fused = rrf_fuse((1..=5).map(point).collect(), vec![point(6)], &options, 10);
+        assert_eq!(fused.len(), 6);
+        assert!(fused.iter().all(|hit| hit.score > options.score_threshold));
+    }
+}
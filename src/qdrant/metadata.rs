@@ -0,0 +1,205 @@
+use crate::api::schema::{DistanceKind, Spec};
+use crate::embeddings::options::EmbeddingOptions;
+use crate::error::handler::EmbeddingsError;
+use crate::indexer::tokens::TokenLimits;
+use custom_logger as log;
+use serde_derive::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Settings a collection was indexed with, stored when the collection is
+// created so a search with different settings can be detected
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CollectionMetadata {
+    pub embedding_model: String,
+    pub dimension: u64,
+    pub query_prefix: String,
+    pub document_prefix: String,
+    pub normalize: bool,
+    // dense + sparse (bm25) named vectors, older metadata has none
+    #[serde(default)]
+    pub hybrid: bool,
+    // older metadata predates the setting, collections were always cosine
+    #[serde(default)]
+    pub distance: DistanceKind,
+    // set when a complete run switched the category alias to the collection,
+    // versions never activated are unfinished or failed runs
+    #[serde(default)]
+    pub activated: bool,
+    pub chunk_strategy: String,
+    pub chunk_size: usize,
+    pub chunk_overlap: usize,
+    pub max_tokens: usize,
+    pub tool_version: String,
+    pub created: u64,
+}
+
+impl CollectionMetadata {
+    pub fn from_spec(spec: &Spec, dimension: u64) -> Self {
+        let options = EmbeddingOptions::from_spec(spec);
+        let limits = TokenLimits::from_spec(spec);
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self {
+            embedding_model: spec.embedding_model.clone(),
+            dimension,
+            query_prefix: options.query_prefix,
+            document_prefix: options.document_prefix,
+            normalize: options.normalize,
+            hybrid: spec.hybrid_search.unwrap_or(false),
+            distance: spec.distance.clone().unwrap_or_default(),
+            activated: false,
+            chunk_strategy: format!("{:?}", spec.strategy()).to_lowercase(),
+            chunk_size: limits.chunk_size,
+            chunk_overlap: limits.chunk_overlap,
+            max_tokens: limits.max_tokens,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created,
+        }
+    }
+
+    // differences that make the query vectors incompatible with the stored ones
+    pub fn embedding_mismatches(&self, other: &CollectionMetadata) -> Vec<String> {
+        let mut result = Vec::new();
+        if self.embedding_model != other.embedding_model {
+            result.push(format!(
+                "embedding model {} (collection) vs {}",
+                self.embedding_model, other.embedding_model
+            ));
+        }
+        if self.dimension != other.dimension {
+            result.push(format!(
+                "dimension {} (collection) vs {}",
+                self.dimension, other.dimension
+            ));
+        }
+        if self.query_prefix != other.query_prefix || self.document_prefix != other.document_prefix
+        {
+            result.push("query/document prefixes differ".to_string());
+        }
+        if self.normalize != other.normalize {
+            result.push(format!(
+                "normalize {} (collection) vs {}",
+                self.normalize, other.normalize
+            ));
+        }
+        if self.hybrid != other.hybrid {
+            result.push(format!(
+                "hybrid {} (collection) vs {}",
+                self.hybrid, other.hybrid
+            ));
+        }
+        if self.distance != other.distance {
+            result.push(format!(
+                "distance {:?} (collection) vs {:?}",
+                self.distance, other.distance
+            ));
+        }
+        result
+    }
+
+    // differences that only affect how new documents would be chunked
+    pub fn chunking_mismatches(&self, other: &CollectionMetadata) -> Vec<String> {
+        let collection = (
+            &self.chunk_strategy,
+            self.chunk_size,
+            self.chunk_overlap,
+            self.max_tokens,
+        );
+        let current = (
+            &other.chunk_strategy,
+            other.chunk_size,
+            other.chunk_overlap,
+            other.max_tokens,
+        );
+        if collection == current {
+            return Vec::new();
+        }
+        vec![format!(
+            "chunking {} size {} overlap {} max tokens {} (collection) vs {} size {} overlap {} max tokens {}",
+            self.chunk_strategy,
+            self.chunk_size,
+            self.chunk_overlap,
+            self.max_tokens,
+            other.chunk_strategy,
+            other.chunk_size,
+            other.chunk_overlap,
+            other.max_tokens
+        )]
+    }
+}
+
+// Compare the stored metadata of a collection with the current settings,
+// chunking or tool version differences are logged, embedding differences
+// are an error unless warn is set
+pub fn verify_metadata(
+    collection: &str,
+    stored: Option<CollectionMetadata>,
+    current: &CollectionMetadata,
+    warn: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(stored) = stored else {
+        log::warn!(
+            "collection {} has no metadata (indexed by an older version), settings not verified",
+            collection
+        );
+        return Ok(());
+    };
+    for mismatch in stored.chunking_mismatches(current).iter() {
+        log::warn!("collection {} : {}", collection, mismatch);
+    }
+    if stored.tool_version != current.tool_version {
+        log::info!(
+            "collection {} was indexed with version {}",
+            collection,
+            stored.tool_version
+        );
+    }
+    let mismatches = stored.embedding_mismatches(current);
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    for mismatch in mismatches.iter() {
+        log::warn!("collection {} : {}", collection, mismatch);
+    }
+    if warn {
+        log::warn!("search results for {} are likely meaningless", collection);
+        return Ok(());
+    }
+    Err(Box::new(EmbeddingsError::new(&format!(
+        "collection {} was indexed with different embedding settings ({}), re-index it or set \
+         \"metadataMismatch\": \"warn\"",
+        collection,
+        mismatches.join(", ")
+    ))))
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn verify_metadata_pass() {
+        let stored = CollectionMetadata {
+            embedding_model: "all-minilm".to_string(),
+            dimension: 384,
+            chunk_size: 200,
+            ..Default::default()
+        };
+        let mut current = stored.clone();
+        current.chunk_size = 100;
+        assert!(stored.embedding_mismatches(&current).is_empty());
+        assert_eq!(stored.chunking_mismatches(&current).len(), 1);
+        assert!(verify_metadata("scripts", Some(stored.clone()), &current, false).is_ok());
+
+        current.embedding_model = "nomic-embed-text".to_string();
+        current.dimension = 768;
+        current.distance = DistanceKind::Euclid;
+        assert_eq!(stored.embedding_mismatches(&current).len(), 3);
+        assert!(verify_metadata("scripts", Some(stored.clone()), &current, false).is_err());
+        assert!(verify_metadata("scripts", Some(stored), &current, true).is_ok());
+        assert!(verify_metadata("scripts", None, &current, false).is_ok());
+    }
+}
@@ -1 +1,4 @@
 pub mod client;
+pub mod hybrid;
+pub mod metadata;
+pub mod params;
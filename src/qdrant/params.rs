@@ -0,0 +1,127 @@
+use crate::api::schema::{DistanceKind, QuantizationKind, Spec};
+use qdrant_client::qdrant::quantization_config::Quantization;
+use qdrant_client::qdrant::{
+    BinaryQuantization, Datatype, Distance, HnswConfigDiff, QuantizationConfig, QuantizationType,
+    ScalarQuantization, SearchParams, VectorParams,
+};
+
+// Index and storage settings of the dense vector, applied when a collection
+// is created. Unset values keep the qdrant defaults
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IndexParams {
+    pub distance: DistanceKind,
+    pub hnsw_m: Option<u64>,
+    pub hnsw_ef_construct: Option<u64>,
+    pub quantization: Option<QuantizationKind>,
+    // keep the quantized vectors in memory when the originals are on disk
+    pub quantization_always_ram: Option<bool>,
+    pub on_disk: Option<bool>,
+    pub float16: bool,
+}
+
+impl IndexParams {
+    pub fn from_spec(spec: &Spec) -> Self {
+        Self {
+            distance: spec.distance.clone().unwrap_or_default(),
+            hnsw_m: spec.hnsw_m,
+            hnsw_ef_construct: spec.hnsw_ef_construct,
+            quantization: spec.quantization.clone(),
+            quantization_always_ram: spec.quantization_always_ram,
+            on_disk: spec.on_disk,
+            float16: spec.float16.unwrap_or(false),
+        }
+    }
+
+    pub fn distance(&self) -> Distance {
+        match self.distance {
+            DistanceKind::Cosine => Distance::Cosine,
+            DistanceKind::Dot => Distance::Dot,
+            DistanceKind::Euclid => Distance::Euclid,
+            DistanceKind::Manhattan => Distance::Manhattan,
+        }
+    }
+
+    fn hnsw_config(&self) -> Option<HnswConfigDiff> {
+        if self.hnsw_m.is_none() && self.hnsw_ef_construct.is_none() {
+            return None;
+        }
+        Some(HnswConfigDiff {
+            m: self.hnsw_m,
+            ef_construct: self.hnsw_ef_construct,
+            ..Default::default()
+        })
+    }
+
+    fn quantization_config(&self) -> Option<QuantizationConfig> {
+        let quantization = match self.quantization.as_ref()? {
+            QuantizationKind::Scalar => Quantization::Scalar(ScalarQuantization {
+                r#type: QuantizationType::Int8.into(),
+                always_ram: self.quantization_always_ram,
+                ..Default::default()
+            }),
+            QuantizationKind::Binary => Quantization::Binary(BinaryQuantization {
+                always_ram: self.quantization_always_ram,
+                ..Default::default()
+            }),
+        };
+        Some(QuantizationConfig {
+            quantization: Some(quantization),
+        })
+    }
+
+    pub fn vector_params(&self, size: u64) -> VectorParams {
+        VectorParams {
+            size,
+            distance: self.distance().into(),
+            hnsw_config: self.hnsw_config(),
+            quantization_config: self.quantization_config(),
+            on_disk: self.on_disk,
+            datatype: self.float16.then(|| Datatype::Float16.into()),
+            multivector_config: None,
+        }
+    }
+}
+
+// per search override of the hnsw ef (candidates kept while searching)
+pub fn search_params(hnsw_ef: Option<u64>) -> Option<SearchParams> {
+    hnsw_ef.map(|ef| SearchParams {
+        hnsw_ef: Some(ef),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn vector_params_pass() {
+        let params = IndexParams::default().vector_params(384);
+        assert_eq!(params.distance, Distance::Cosine as i32);
+        assert_eq!(params.hnsw_config, None);
+        assert_eq!(params.quantization_config, None);
+        assert_eq!(params.datatype, None);
+
+        let params = IndexParams {
+            distance: DistanceKind::Dot,
+            hnsw_m: Some(32),
+            quantization: Some(QuantizationKind::Scalar),
+            on_disk: Some(true),
+            float16: true,
+            ..Default::default()
+        }
+        .vector_params(384);
+        assert_eq!(params.distance, Distance::Dot as i32);
+        assert_eq!(params.hnsw_config.unwrap().m, Some(32));
+        assert_eq!(params.hnsw_config.unwrap().ef_construct, None);
+        assert!(matches!(
+            params.quantization_config.unwrap().quantization,
+            Some(Quantization::Scalar(_))
+        ));
+        assert_eq!(params.on_disk, Some(true));
+        assert_eq!(params.datatype, Some(Datatype::Float16 as i32));
+        assert_eq!(search_params(None), None);
+        assert_eq!(search_params(Some(128)).unwrap().hnsw_ef, Some(128));
+    }
+}
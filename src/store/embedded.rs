@@ -0,0 +1,648 @@
+use crate::api::schema::DistanceKind;
+use crate::error::handler::EmbeddingsError;
+use crate::qdrant::hybrid::{bm25_query, rrf_fuse, HybridOptions, SparseVector, PREFETCH_FACTOR};
+use crate::qdrant::metadata::CollectionMetadata;
+use crate::store::filter::SearchFilter;
+use crate::store::vector::{PointPayload, SearchHit, SearchQuery, StorePoint, VectorStore};
+use async_trait::async_trait;
+use custom_logger as log;
+use redb::{Database, ReadableTable, TableDefinition};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// key collection name, value the collection metadata (json)
+const COLLECTIONS: TableDefinition<&str, &str> = TableDefinition::new("collections");
+// key alias, value the collection name
+const ALIASES: TableDefinition<&str, &str> = TableDefinition::new("aliases");
+
+// one "points:<collection>" table per collection, key point id, value json
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct StoredPoint {
+    vector: Vec<f32>,
+    #[serde(default)]
+    sparse: Option<SparseVector>,
+    payload: PointPayload,
+}
+
+type Points = Arc<Vec<(String, StoredPoint)>>;
+
+fn points_table(collection: &str) -> String {
+    format!("points:{}", collection)
+}
+
+fn not_found(collection: &str) -> Box<dyn std::error::Error> {
+    Box::new(EmbeddingsError::new(&format!(
+        "collection {} doesn't exist in the embedded store, index it first",
+        collection
+    )))
+}
+
+// Vector store in a single local file, search is brute force over every
+// point of the collection so it suits knowledge bases up to a few hundred
+// thousand chunks. Collections are loaded in memory on the first search
+pub struct EmbeddedStore {
+    db: Database,
+    hybrid: Option<HybridOptions>,
+    distance: DistanceKind,
+    loaded: Mutex<HashMap<String, Points>>,
+}
+
+impl EmbeddedStore {
+    pub fn open(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let db = Database::create(path)?;
+        // make sure the tables exist for readers
+        let txn = db.begin_write()?;
+        txn.open_table(COLLECTIONS)?;
+        txn.open_table(ALIASES)?;
+        txn.commit()?;
+        Ok(Self {
+            db,
+            hybrid: None,
+            distance: DistanceKind::default(),
+            loaded: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn with_hybrid(mut self, hybrid: Option<HybridOptions>) -> Self {
+        self.hybrid = hybrid;
+        self
+    }
+
+    pub fn with_distance(mut self, distance: DistanceKind) -> Self {
+        self.distance = distance;
+        self
+    }
+
+    // the collection behind an alias, other names are returned as is
+    fn resolve(&self, name: String) -> Result<String, Box<dyn std::error::Error>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(ALIASES)?;
+        let target = table.get(name.as_str())?;
+        Ok(target.map_or(name, |target| target.value().to_string()))
+    }
+
+    // drop the in memory copy after a write
+    fn invalidate(&self, collection: &str) {
+        self.loaded.lock().unwrap().remove(collection);
+    }
+
+    fn create_collection(
+        &self,
+        collection: &str,
+        metadata: &CollectionMetadata,
+        reset: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        log::info!(
+            "creating collection {} (vector size {})",
+            collection,
+            metadata.dimension
+        );
+        let name = points_table(collection);
+        let table: TableDefinition<&str, &[u8]> = TableDefinition::new(&name);
+        let txn = self.db.begin_write()?;
+        if reset {
+            txn.delete_table(table)?;
+        }
+        txn.open_table(table)?;
+        {
+            let mut collections = txn.open_table(COLLECTIONS)?;
+            collections.insert(collection, serde_json::to_string(metadata)?.as_str())?;
+        }
+        txn.commit()?;
+        self.invalidate(collection);
+        Ok(())
+    }
+
+    fn load(&self, collection: &str) -> Result<Points, Box<dyn std::error::Error>> {
+        if let Some(points) = self.loaded.lock().unwrap().get(collection) {
+            return Ok(points.clone());
+        }
+        let name = points_table(collection);
+        let table: TableDefinition<&str, &[u8]> = TableDefinition::new(&name);
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(table).map_err(|_| not_found(collection))?;
+        let mut points = Vec::new();
+        for entry in table.iter()? {
+            let (id, value) = entry?;
+            let point: StoredPoint = serde_json::from_slice(value.value())?;
+            points.push((id.value().to_string(), point));
+        }
+        log::debug!("loaded {} points from {}", points.len(), collection);
+        let points = Arc::new(points);
+        self.loaded
+            .lock()
+            .unwrap()
+            .insert(collection.to_string(), points.clone());
+        Ok(points)
+    }
+
+    // closest points (matching the filter) first
+    fn dense_search(
+        &self,
+        points: &[(String, StoredPoint)],
+        filter: &SearchFilter,
+        embedding: &[f32],
+        limit: usize,
+    ) -> Vec<SearchHit> {
+        let mut hits: Vec<SearchHit> = points
+            .iter()
+            .filter(|(_, point)| filter.matches(&point.payload))
+            .map(|(id, point)| hit(id, score(&self.distance, embedding, &point.vector), point))
+            .collect();
+        if self.distance.lower_is_closer() {
+            hits.sort_by(|a, b| a.score.total_cmp(&b.score));
+            hits.truncate(limit);
+            return hits;
+        }
+        top(hits, limit)
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let mut dot = 0.0;
+    let mut norm_a = 0.0;
+    let mut norm_b = 0.0;
+    for (x, y) in a.iter().zip(b.iter()) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    let norm = (norm_a * norm_b).sqrt();
+    if norm > 0.0 {
+        dot / norm
+    } else {
+        0.0
+    }
+}
+
+// scores as qdrant reports them, a similarity for cosine and dot and a
+// distance (lower is closer) for euclid and manhattan
+fn score(distance: &DistanceKind, a: &[f32], b: &[f32]) -> f32 {
+    let pairs = a.iter().zip(b.iter());
+    match distance {
+        DistanceKind::Cosine => cosine(a, b),
+        DistanceKind::Dot => pairs.map(|(x, y)| x * y).sum(),
+        DistanceKind::Euclid => pairs.map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt(),
+        DistanceKind::Manhattan => pairs.map(|(x, y)| (x - y).abs()).sum(),
+    }
+}
+
+fn top(mut hits: Vec<SearchHit>, limit: usize) -> Vec<SearchHit> {
+    hits.sort_by(|a, b| b.score.total_cmp(&a.score));
+    hits.truncate(limit);
+    hits
+}
+
+fn hit(id: &str, score: f32, point: &StoredPoint) -> SearchHit {
+    SearchHit {
+        id: id.to_string(),
+        score,
+        payload: point.payload.clone(),
+    }
+}
+
+// bm25 scores : the stored document weights times the query weights times
+// the idf of each term, computed over the whole collection (not only the
+// points matching the filter) as qdrant does
+fn sparse_search(
+    points: &[(String, StoredPoint)],
+    filter: &SearchFilter,
+    query: &SparseVector,
+    limit: usize,
+) -> Vec<SearchHit> {
+    let mut frequency: HashMap<u32, f32> = HashMap::new();
+    for (_, point) in points.iter() {
+        let Some(sparse) = point.sparse.as_ref() else {
+            continue;
+        };
+        for index in query.indices.iter() {
+            if sparse.indices.binary_search(index).is_ok() {
+                *frequency.entry(*index).or_default() += 1.0;
+            }
+        }
+    }
+    let total = points.len() as f32;
+    let idf: HashMap<u32, f32> = frequency
+        .into_iter()
+        .map(|(index, n)| (index, (1.0 + (total - n + 0.5) / (n + 0.5)).ln()))
+        .collect();
+    let mut hits = Vec::new();
+    for (id, point) in points.iter() {
+        let Some(sparse) = point.sparse.as_ref() else {
+            continue;
+        };
+        if !filter.matches(&point.payload) {
+            continue;
+        }
+        let mut score = 0.0;
+        for (index, weight) in query.indices.iter().zip(query.values.iter()) {
+            if let Ok(pos) = sparse.indices.binary_search(index) {
+                score += weight * sparse.values[pos] * idf.get(index).unwrap_or(&0.0);
+            }
+        }
+        if score > 0.0 {
+            hits.push(hit(id, score, point));
+        }
+    }
+    top(hits, limit)
+}
+
+#[async_trait]
+impl VectorStore for EmbeddedStore {
+    fn name(&self) -> &str {
+        "embedded"
+    }
+
+    fn hybrid(&self) -> Option<&HybridOptions> {
+        self.hybrid.as_ref()
+    }
+
+    async fn reset_collection(
+        &self,
+        collection: String,
+        metadata: &CollectionMetadata,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.create_collection(&collection, metadata, true)
+    }
+
+    async fn ensure_collection(
+        &self,
+        collection: String,
+        metadata: &CollectionMetadata,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self.resolve(collection)?;
+        if self.read_metadata(collection.clone()).await?.is_some() {
+            return self.check_vector_size(collection, metadata.dimension).await;
+        }
+        self.create_collection(&collection, metadata, false)
+    }
+
+    // every embedded collection is created with its metadata
+    async fn read_metadata(
+        &self,
+        collection: String,
+    ) -> Result<Option<CollectionMetadata>, Box<dyn std::error::Error>> {
+        let collection = self.resolve(collection)?;
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(COLLECTIONS)?;
+        let value = table.get(collection.as_str())?;
+        match value {
+            Some(metadata) => Ok(Some(serde_json::from_str(metadata.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn write_metadata(
+        &self,
+        collection: String,
+        metadata: &CollectionMetadata,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self.resolve(collection)?;
+        let txn = self.db.begin_write()?;
+        txn.open_table(COLLECTIONS)?.insert(
+            collection.as_str(),
+            serde_json::to_string(metadata)?.as_str(),
+        )?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    async fn vector_size(&self, collection: String) -> Result<u64, Box<dyn std::error::Error>> {
+        let metadata = self
+            .read_metadata(collection.clone())
+            .await?
+            .ok_or_else(|| not_found(&collection))?;
+        if metadata.hybrid != self.hybrid.is_some() {
+            return Err(Box::new(EmbeddingsError::new(&format!(
+                "collection {} was indexed with hybridSearch {}, re-index it",
+                collection, metadata.hybrid
+            ))));
+        }
+        Ok(metadata.dimension)
+    }
+
+    async fn upsert_points(
+        &self,
+        collection: String,
+        points: Vec<StorePoint>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self.resolve(collection)?;
+        let name = points_table(&collection);
+        let table: TableDefinition<&str, &[u8]> = TableDefinition::new(&name);
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(table)?;
+            for point in points.into_iter() {
+                let value = serde_json::to_vec(&StoredPoint {
+                    vector: point.vector,
+                    sparse: point.sparse,
+                    payload: point.payload,
+                })?;
+                table.insert(point.id.as_str(), value.as_slice())?;
+            }
+        }
+        txn.commit()?;
+        self.invalidate(&collection);
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        collection: String,
+        query: SearchQuery,
+    ) -> Result<Vec<SearchHit>, Box<dyn std::error::Error>> {
+        let collection = self.resolve(collection)?;
+        let points = self.load(&collection)?;
+        let Some(hybrid) = self.hybrid.as_ref() else {
+            return Ok(self.dense_search(
+                &points,
+                &query.filter,
+                &query.embedding,
+                query.limit as usize,
+            ));
+        };
+
+        let limit = (query.limit * PREFETCH_FACTOR) as usize;
+        let dense = self.dense_search(&points, &query.filter, &query.embedding, limit);
+        let sparse = sparse_search(&points, &query.filter, &bm25_query(&query.text), limit);
+        log::debug!(
+            "hybrid search : {} dense, {} sparse results",
+            dense.len(),
+            sparse.len()
+        );
+        Ok(rrf_fuse(dense, sparse, hybrid, query.limit as usize))
+    }
+
+    async fn delete_files(
+        &self,
+        collection: String,
+        files: Vec<String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self.resolve(collection)?;
+        if files.is_empty() {
+            return Ok(());
+        }
+        let files: HashSet<String> = files.into_iter().collect();
+        let name = points_table(&collection);
+        let table: TableDefinition<&str, &[u8]> = TableDefinition::new(&name);
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(table)?;
+            table.retain(|_, value| {
+                let Ok(point) = serde_json::from_slice::<StoredPoint>(value) else {
+                    return true;
+                };
+                let file = point.payload.get("file").and_then(|f| f.as_str());
+                let dirs = point.payload.get("dirs").and_then(|d| d.as_array());
+                let in_dir = dirs.is_some_and(|dirs| {
+                    dirs.iter()
+                        .any(|dir| dir.as_str().is_some_and(|dir| files.contains(dir)))
+                });
+                !in_dir && !file.is_some_and(|f| files.contains(f))
+            })?;
+        }
+        txn.commit()?;
+        self.invalidate(&collection);
+        Ok(())
+    }
+
+    async fn delete_stale(
+        &self,
+        collection: String,
+        files: HashMap<String, String>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let collection = self.resolve(collection)?;
+        if files.is_empty() {
+            return Ok(());
+        }
+        let name = points_table(&collection);
+        let table: TableDefinition<&str, &[u8]> = TableDefinition::new(&name);
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(table)?;
+            table.retain(|_, value| {
+                let Ok(point) = serde_json::from_slice::<StoredPoint>(value) else {
+                    return true;
+                };
+                let field = |key: &str| point.payload.get(key).and_then(|v| v.as_str());
+                match field("file").and_then(|file| files.get(file)) {
+                    Some(hash) => field("hash") == Some(hash.as_str()),
+                    None => true,
+                }
+            })?;
+        }
+        txn.commit()?;
+        self.invalidate(&collection);
+        Ok(())
+    }
+
+    async fn list_collections(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(COLLECTIONS)?;
+        let mut result = Vec::new();
+        for entry in table.iter()? {
+            let (name, _) = entry?;
+            result.push(name.value().to_string());
+        }
+        Ok(result)
+    }
+
+    async fn delete_collection(
+        &self,
+        collection: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        log::info!("deleting collection {}", collection);
+        let name = points_table(&collection);
+        let table: TableDefinition<&str, &[u8]> = TableDefinition::new(&name);
+        let txn = self.db.begin_write()?;
+        txn.delete_table(table)?;
+        txn.open_table(COLLECTIONS)?.remove(collection.as_str())?;
+        txn.commit()?;
+        self.invalidate(&collection);
+        Ok(())
+    }
+
+    async fn alias_target(
+        &self,
+        alias: String,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(ALIASES)?;
+        let target = table.get(alias.as_str())?;
+        Ok(target.map(|target| target.value().to_string()))
+    }
+
+    async fn switch_alias(
+        &self,
+        alias: String,
+        collection: String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let txn = self.db.begin_write()?;
+        txn.open_table(ALIASES)?
+            .insert(alias.as_str(), collection.as_str())?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    async fn scroll(
+        &self,
+        collection: String,
+        fields: &[&str],
+    ) -> Result<Vec<PointPayload>, Box<dyn std::error::Error>> {
+        let collection = self.resolve(collection)?;
+        let points = self.load(&collection)?;
+        let result = points
+            .iter()
+            .map(|(_, point)| {
+                point
+                    .payload
+                    .iter()
+                    .filter(|(key, _)| fields.contains(&key.as_str()))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect()
+            })
+            .collect();
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+    use crate::store::filter::parent_dirs;
+    use serde_json::json;
+
+    fn point(file: &str, chunk: usize, vector: Vec<f32>) -> StorePoint {
+        let mut payload = PointPayload::new();
+        payload.insert("file".to_string(), json!(file));
+        payload.insert("hash".to_string(), json!(format!("hash-{}", file)));
+        payload.insert("mtime".to_string(), json!(10));
+        payload.insert("dirs".to_string(), json!(parent_dirs(file)));
+        StorePoint {
+            id: format!("{}#{}", file, chunk),
+            vector,
+            sparse: None,
+            payload,
+        }
+    }
+
+    #[tokio::test]
+    async fn embedded_store_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.redb");
+        let path = path.to_str().unwrap();
+        let metadata = CollectionMetadata {
+            dimension: 2,
+            ..Default::default()
+        };
+        let store = EmbeddedStore::open(path).unwrap();
+        store
+            .reset_collection("scripts".to_string(), &metadata)
+            .await
+            .unwrap();
+        let points = vec![
+            point("deploy.sh", 0, vec![1.0, 0.0]),
+            point("deploy.sh", 1, vec![0.7, 0.7]),
+            point("rollback.sh", 0, vec![0.0, 1.0]),
+        ];
+        store
+            .upsert_points("scripts".to_string(), points)
+            .await
+            .unwrap();
+        let query = SearchQuery {
+            embedding: vec![0.0, 2.0],
+            text: "rollback".to_string(),
+            limit: 2,
+            ..Default::default()
+        };
+        let hits = store
+            .search("scripts".to_string(), query.clone())
+            .await
+            .unwrap();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].id, "rollback.sh#0");
+        assert!((hits[0].score - 1.0).abs() < 1e-6);
+        assert_eq!(hits[1].id, "deploy.sh#1");
+        let filtered = SearchQuery {
+            filter: SearchFilter::parse("path:deploy.sh").unwrap(),
+            ..query.clone()
+        };
+        let hits = store.search("scripts".to_string(), filtered).await.unwrap();
+        assert_eq!(hits.len(), 2);
+        assert!(hits.iter().all(|hit| hit.id.starts_with("deploy.sh")));
+
+        store
+            .delete_files("scripts".to_string(), vec!["rollback.sh".to_string()])
+            .await
+            .unwrap();
+        drop(store);
+
+        // everything is persisted in the file
+        let store = EmbeddedStore::open(path)
+            .unwrap()
+            .with_distance(DistanceKind::Euclid);
+        assert!(store
+            .check_vector_size("scripts".to_string(), 2)
+            .await
+            .is_ok());
+        assert!(store
+            .check_vector_size("scripts".to_string(), 3)
+            .await
+            .is_err());
+        let files = store.indexed_files("scripts".to_string()).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files["deploy.sh"].hash, "hash-deploy.sh");
+        let hits = store.search("scripts".to_string(), query).await.unwrap();
+        assert_eq!(hits[0].id, "deploy.sh#1");
+        assert!(hits[0].score < hits[1].score);
+        assert!(store.vector_size("docs".to_string()).await.is_err());
+
+        // only the points written for another version of the file are stale
+        let current = HashMap::from([("deploy.sh".to_string(), "hash-deploy.sh".to_string())]);
+        store
+            .delete_stale("scripts".to_string(), current)
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .scroll("scripts".to_string(), &["file"])
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        let changed = HashMap::from([("deploy.sh".to_string(), "hash-new".to_string())]);
+        store
+            .delete_stale("scripts".to_string(), changed)
+            .await
+            .unwrap();
+        assert!(store
+            .scroll("scripts".to_string(), &["file"])
+            .await
+            .unwrap()
+            .is_empty());
+
+        // deleting a directory removes the points of every file below it
+        let points = vec![point("ops/deploy/run.sh", 0, vec![1.0, 0.0])];
+        store
+            .upsert_points("scripts".to_string(), points)
+            .await
+            .unwrap();
+        store
+            .delete_files("scripts".to_string(), vec!["ops".to_string()])
+            .await
+            .unwrap();
+        assert!(store
+            .scroll("scripts".to_string(), &["file"])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,240 @@
+use crate::error::handler::EmbeddingsError;
+use crate::markdown::frontmatter::parse_date;
+use crate::store::vector::PointPayload;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+
+// end of the day for the inclusive before date
+const DAY_SECONDS: u64 = 86400;
+
+// Restricts a search to the points whose payload matches every set field.
+// Dates are "YYYY-MM-DD" and compared with the front matter date of the file
+// (its modification time without one), both bounds are inclusive
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchFilter {
+    // a directory or a file, matched on whole path segments against the file
+    // payload (relative to the working directory, i.e. kb-docs/scripts/ops)
+    pub path: Option<String>,
+    // any of these extensions (without the dot)
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    // all of these front matter tags
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub after: Option<String>,
+    pub before: Option<String>,
+}
+
+// directories above the file, "ops/deploy/run.sh" gives ["ops", "ops/deploy"]
+pub fn parent_dirs(file: &str) -> Vec<String> {
+    let parts: Vec<&str> = file.split('/').filter(|part| !part.is_empty()).collect();
+    (1..parts.len()).map(|n| parts[..n].join("/")).collect()
+}
+
+// lower case extension of the file, none for files without one
+pub fn file_extension(file: &str) -> Option<String> {
+    let name = file.rsplit('/').next()?;
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => Some(extension.to_lowercase()),
+        _ => None,
+    }
+}
+
+impl SearchFilter {
+    // Parse the chat syntax, space separated key:value terms where lists are
+    // comma separated : path:ops/deploy ext:sh,md tag:ocp after:2024-01-01
+    pub fn parse(text: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut filter = Self::default();
+        for term in text.split_whitespace() {
+            let Some((key, value)) = term.split_once(':') else {
+                return Err(Box::new(EmbeddingsError::new(&format!(
+                    "filter term {} is not key:value",
+                    term
+                ))));
+            };
+            let list = || -> Vec<String> {
+                value
+                    .split(',')
+                    .filter(|v| !v.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            };
+            match key {
+                "path" => filter.path = Some(value.to_string()),
+                "ext" => filter.extensions = list(),
+                "tag" => filter.tags = list(),
+                "after" => filter.after = Some(value.to_string()),
+                "before" => filter.before = Some(value.to_string()),
+                _ => {
+                    return Err(Box::new(EmbeddingsError::new(&format!(
+                        "unknown filter {}, use path, ext, tag, after or before",
+                        key
+                    ))))
+                }
+            }
+        }
+        filter.date_range()?;
+        Ok(filter)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // the path without leading or trailing '/'
+    pub fn trimmed_path(&self) -> Option<String> {
+        self.path
+            .as_deref()
+            .map(|path| path.trim_start_matches("./").trim_matches('/').to_string())
+            .filter(|path| !path.is_empty())
+    }
+
+    // extensions without the dot, lower case
+    pub fn extensions(&self) -> Vec<String> {
+        self.extensions
+            .iter()
+            .map(|ext| ext.trim_start_matches('.').to_lowercase())
+            .collect()
+    }
+
+    // (after, before) in seconds since epoch, before is the end of its day
+    pub fn date_range(&self) -> Result<(Option<u64>, Option<u64>), Box<dyn std::error::Error>> {
+        let parse = |value: &Option<String>| -> Result<Option<u64>, Box<dyn std::error::Error>> {
+            match value {
+                None => Ok(None),
+                Some(value) => parse_date(value).map(Some).ok_or_else(|| {
+                    Box::new(EmbeddingsError::new(&format!(
+                        "invalid date {}, expected YYYY-MM-DD",
+                        value
+                    ))) as Box<dyn std::error::Error>
+                }),
+            }
+        };
+        let after = parse(&self.after)?;
+        let before = parse(&self.before)?.map(|before| before + DAY_SECONDS - 1);
+        Ok((after, before))
+    }
+
+    // same semantics as the qdrant filter, for stores that filter themselves
+    pub fn matches(&self, payload: &PointPayload) -> bool {
+        let strings = |key: &str| -> Vec<&str> {
+            match payload.get(key) {
+                Some(Value::Array(values)) => values.iter().filter_map(|v| v.as_str()).collect(),
+                Some(Value::String(value)) => vec![value.as_str()],
+                _ => Vec::new(),
+            }
+        };
+        if let Some(path) = self.trimmed_path() {
+            let in_dir = strings("dirs").contains(&path.as_str());
+            if !in_dir && !strings("file").contains(&path.as_str()) {
+                return false;
+            }
+        }
+        let extensions = self.extensions();
+        if !extensions.is_empty()
+            && !strings("extension")
+                .iter()
+                .any(|ext| extensions.iter().any(|e| e == ext))
+        {
+            return false;
+        }
+        let tags = strings("tags");
+        if !self.tags.iter().all(|tag| tags.contains(&tag.as_str())) {
+            return false;
+        }
+        let Ok((after, before)) = self.date_range() else {
+            return false;
+        };
+        if after.is_some() || before.is_some() {
+            let Some(date) = payload.get("date").and_then(|v| v.as_u64()) else {
+                return false;
+            };
+            if after.is_some_and(|after| date < after) || before.is_some_and(|before| date > before)
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// the chat syntax, an empty filter shows as "none"
+impl fmt::Display for SearchFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut terms = Vec::new();
+        if let Some(path) = self.path.as_ref() {
+            terms.push(format!("path:{}", path));
+        }
+        if !self.extensions.is_empty() {
+            terms.push(format!("ext:{}", self.extensions.join(",")));
+        }
+        if !self.tags.is_empty() {
+            terms.push(format!("tag:{}", self.tags.join(",")));
+        }
+        if let Some(after) = self.after.as_ref() {
+            terms.push(format!("after:{}", after));
+        }
+        if let Some(before) = self.before.as_ref() {
+            terms.push(format!("before:{}", before));
+        }
+        if terms.is_empty() {
+            return write!(f, "none");
+        }
+        write!(f, "{}", terms.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn search_filter_pass() {
+        assert_eq!(parent_dirs("ops/deploy/run.sh"), vec!["ops", "ops/deploy"]);
+        assert!(parent_dirs("run.sh").is_empty());
+        assert_eq!(file_extension("ops/Run.SH"), Some("sh".to_string()));
+        assert_eq!(file_extension("ops/.bashrc"), None);
+
+        let filter = SearchFilter::parse(
+            "path:./ops/deploy/ ext:.SH,md tag:ocp after:2024-01-01 before:2024-01-31",
+        )
+        .unwrap();
+        assert_eq!(filter.trimmed_path(), Some("ops/deploy".to_string()));
+        assert_eq!(filter.extensions(), vec!["sh", "md"]);
+        assert_eq!(
+            filter.to_string(),
+            "path:./ops/deploy/ ext:.SH,md tag:ocp after:2024-01-01 before:2024-01-31"
+        );
+        assert!(SearchFilter::parse("tag").is_err());
+        assert!(SearchFilter::parse("size:10").is_err());
+        assert!(SearchFilter::parse("after:yesterday").is_err());
+        assert_eq!(SearchFilter::default().to_string(), "none");
+
+        let mut payload = PointPayload::new();
+        payload.insert("file".to_string(), json!("ops/deploy/run.sh"));
+        payload.insert("dirs".to_string(), json!(parent_dirs("ops/deploy/run.sh")));
+        payload.insert("extension".to_string(), json!("sh"));
+        payload.insert("tags".to_string(), json!(["ocp", "mirror"]));
+        // 2024-01-31 12:00 utc
+        payload.insert("date".to_string(), json!(1706702400));
+        assert!(filter.matches(&payload));
+        assert!(SearchFilter::default().matches(&payload));
+        assert!(SearchFilter::parse("path:ops/deploy/run.sh")
+            .unwrap()
+            .matches(&payload));
+        assert!(!SearchFilter::parse("path:ops/dep")
+            .unwrap()
+            .matches(&payload));
+        assert!(!SearchFilter::parse("ext:md").unwrap().matches(&payload));
+        assert!(!SearchFilter::parse("tag:ocp,k8s")
+            .unwrap()
+            .matches(&payload));
+        assert!(!SearchFilter::parse("before:2024-01-30")
+            .unwrap()
+            .matches(&payload));
+    }
+}
@@ -0,0 +1,3 @@
+pub mod embedded;
+pub mod filter;
+pub mod vector;
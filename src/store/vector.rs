@@ -0,0 +1,292 @@
+use crate::api::schema::{Spec, VectorStoreKind};
+use crate::error::handler::EmbeddingsError;
+use crate::qdrant::client::VectorDB;
+use crate::qdrant::hybrid::{bm25_document, HybridOptions, SparseVector};
+use crate::qdrant::metadata::CollectionMetadata;
+use crate::qdrant::params::IndexParams;
+use crate::store::embedded::EmbeddedStore;
+use crate::store::filter::{file_extension, parent_dirs, SearchFilter};
+use crate::MarkdownFile;
+use async_trait::async_trait;
+use custom_logger as log;
+use qdrant_client::Qdrant;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+// embedded store location when not set in the config
+const DEFAULT_VECTOR_STORE_PATH: &str = ".vectors/store.redb";
+
+// json payload of a point, the same fields in every store
+pub type PointPayload = Map<String, Value>;
+
+// State of a source file as recorded in the collection payload
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexedFile {
+    pub hash: String,
+    pub mtime: u64,
+}
+
+// An embedded chunk ready to be written, the sparse (bm25) vector is only set
+// for hybrid collections
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StorePoint {
+    pub id: String,
+    pub vector: Vec<f32>,
+    pub sparse: Option<SparseVector>,
+    pub payload: PointPayload,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchHit {
+    pub id: String,
+    pub score: f32,
+    pub payload: PointPayload,
+}
+
+// query embedding plus the raw text, the text is used by the bm25 side of a
+// hybrid search
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    pub embedding: Vec<f32>,
+    pub text: String,
+    pub limit: u64,
+    // overrides the hnsw ef of the collection (qdrant only)
+    pub hnsw_ef: Option<u64>,
+    // only points matching the filter are returned
+    pub filter: SearchFilter,
+}
+
+// Storage and search of the embedded chunks, one collection per category
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    fn name(&self) -> &str;
+
+    // set when collections hold a dense and a sparse (bm25) vector
+    fn hybrid(&self) -> Option<&HybridOptions>;
+
+    // recreate the collection, the metadata gives the vector size and is
+    // stored with the new collection
+    async fn reset_collection(
+        &self,
+        collection: String,
+        metadata: &CollectionMetadata,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    // create the collection only if it does not already exist, an existing
+    // collection must have the same vector size
+    async fn ensure_collection(
+        &self,
+        collection: String,
+        metadata: &CollectionMetadata,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    // settings stored for the collection, none when it was created by an
+    // older version
+    async fn read_metadata(
+        &self,
+        collection: String,
+    ) -> Result<Option<CollectionMetadata>, Box<dyn std::error::Error>>;
+
+    // replace the settings stored for the collection
+    async fn write_metadata(
+        &self,
+        collection: String,
+        metadata: &CollectionMetadata,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    // vector size of an existing collection
+    async fn vector_size(&self, collection: String) -> Result<u64, Box<dyn std::error::Error>>;
+
+    async fn upsert_points(
+        &self,
+        collection: String,
+        points: Vec<StorePoint>,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    async fn search(
+        &self,
+        collection: String,
+        query: SearchQuery,
+    ) -> Result<Vec<SearchHit>, Box<dyn std::error::Error>>;
+
+    // delete every point that was read from one of the given source files, or
+    // from a file below one of them when it is a directory
+    async fn delete_files(
+        &self,
+        collection: String,
+        files: Vec<String>,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    // delete the points of each file (file -> current hash) that were written
+    // for another version of it, the chunks a re-indexed file no longer has
+    async fn delete_stale(
+        &self,
+        collection: String,
+        files: HashMap<String, String>,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    // payload (only the given fields) of every point in the collection
+    async fn scroll(
+        &self,
+        collection: String,
+        fields: &[&str],
+    ) -> Result<Vec<PointPayload>, Box<dyn std::error::Error>>;
+
+    // names of the collections (not the aliases) in the store
+    async fn list_collections(&self) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+
+    // drop the collection and its metadata
+    async fn delete_collection(&self, collection: String)
+        -> Result<(), Box<dyn std::error::Error>>;
+
+    // collection the alias points to, none when there is no such alias
+    async fn alias_target(
+        &self,
+        alias: String,
+    ) -> Result<Option<String>, Box<dyn std::error::Error>>;
+
+    // create the alias or point it at another collection in one step, a
+    // search through the alias never sees it missing
+    async fn switch_alias(
+        &self,
+        alias: String,
+        collection: String,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+
+    // fail with a clear message when the collection was created for a
+    // different embedding model
+    async fn check_vector_size(
+        &self,
+        collection: String,
+        size: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let existing = self.vector_size(collection.clone()).await?;
+        if existing != size {
+            return Err(Box::new(EmbeddingsError::new(&format!(
+                "collection {} has vector size {} but the embedding model produces {}, \
+                 re-index the collection (without --incremental) after changing embeddingModel",
+                collection, existing, size
+            ))));
+        }
+        Ok(())
+    }
+
+    // hash and mtime recorded for every source file in the collection
+    async fn indexed_files(
+        &self,
+        collection: String,
+    ) -> Result<HashMap<String, IndexedFile>, Box<dyn std::error::Error>> {
+        let payloads = self.scroll(collection, &["file", "hash", "mtime"]).await?;
+        let mut result = HashMap::new();
+        for payload in payloads.iter() {
+            let file = payload.get("file").and_then(|v| v.as_str());
+            let hash = payload.get("hash").and_then(|v| v.as_str());
+            let mtime = payload.get("mtime").and_then(|v| v.as_u64());
+            if let (Some(file), Some(hash)) = (file, hash) {
+                result.insert(
+                    file.to_string(),
+                    IndexedFile {
+                        hash: hash.to_string(),
+                        mtime: mtime.unwrap_or(0),
+                    },
+                );
+            }
+        }
+        Ok(result)
+    }
+}
+
+// Qdrant (default) or the embedded file backed store, selected with vectorStore
+pub fn new_store(spec: &Spec) -> Result<Arc<dyn VectorStore>, Box<dyn std::error::Error>> {
+    let hybrid = HybridOptions::from_spec(spec);
+    let params = IndexParams::from_spec(spec);
+    match spec.vector_store.clone().unwrap_or_default() {
+        VectorStoreKind::Qdrant => {
+            let url = format!("{}:{}", spec.qdrant_url, spec.qdrant_port);
+            log::debug!("qdrant {}", url);
+            let client = Qdrant::from_url(&url).build()?;
+            Ok(Arc::new(
+                VectorDB::new(client)
+                    .with_hybrid(hybrid)
+                    .with_params(params),
+            ))
+        }
+        VectorStoreKind::Embedded => {
+            let path = spec
+                .vector_store_path
+                .clone()
+                .unwrap_or(DEFAULT_VECTOR_STORE_PATH.to_string());
+            log::debug!("embedded vector store {}", path);
+            Ok(Arc::new(
+                EmbeddedStore::open(&path)?
+                    .with_hybrid(hybrid)
+                    .with_distance(params.distance),
+            ))
+        }
+    }
+}
+
+// Deterministic point id for a chunk, a UUIDv5 (url namespace) of "<path>#<chunk>"
+// so re-upserting the same chunk overwrites the same point
+pub fn point_id(path: &str, chunk: usize) -> String {
+    Uuid::new_v5(
+        &Uuid::NAMESPACE_URL,
+        format!("{}#{}", path, chunk).as_bytes(),
+    )
+    .to_string()
+}
+
+// Build the point (with payload) for an embedded chunk, hybrid points also get
+// a bm25 sparse vector of the chunk contents
+pub fn to_point(
+    embedding: Vec<f32>,
+    mkd_file: &MarkdownFile,
+    hybrid: Option<&HybridOptions>,
+) -> StorePoint {
+    let mut payload = PointPayload::new();
+    payload.insert("id".to_string(), json!(mkd_file.path));
+    payload.insert("chunk".to_string(), json!(mkd_file.chunk));
+    payload.insert("breadcrumb".to_string(), json!(mkd_file.breadcrumb));
+    payload.insert("contents".to_string(), json!(mkd_file.contents));
+    payload.insert("file".to_string(), json!(mkd_file.file));
+    payload.insert("hash".to_string(), json!(mkd_file.hash));
+    payload.insert("mtime".to_string(), json!(mkd_file.mtime));
+    // fields used by search filters (all indexed in qdrant)
+    payload.insert("dirs".to_string(), json!(parent_dirs(&mkd_file.file)));
+    if let Some(extension) = file_extension(&mkd_file.file) {
+        payload.insert("extension".to_string(), json!(extension));
+    }
+    payload.insert("tags".to_string(), json!(mkd_file.tags));
+    payload.insert("date".to_string(), json!(mkd_file.date));
+    // header capture groups, these never override the fields above
+    for (name, field) in mkd_file.fields.iter() {
+        if payload.contains_key(name) {
+            log::warn!("header field {} is reserved, skipping", name);
+            continue;
+        }
+        payload.insert(name.clone(), json!(field));
+    }
+    StorePoint {
+        id: point_id(&mkd_file.path, mkd_file.chunk),
+        vector: embedding,
+        sparse: hybrid.map(|hybrid| bm25_document(&mkd_file.contents, hybrid.avg_length)),
+        payload,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // this brings everything from parent's scope into this scope
+    use super::*;
+
+    #[test]
+    fn point_id_pass() {
+        let id = point_id("./kb-docs/scripts/deploy.sh", 0);
+        assert_eq!(id, point_id("./kb-docs/scripts/deploy.sh", 0));
+        assert_ne!(id, point_id("./kb-docs/scripts/deploy.sh", 1));
+        assert_ne!(id, point_id("./kb-docs/scripts/rollback.sh", 0));
+        assert_eq!(Uuid::parse_str(&id).unwrap().get_version_num(), 5);
+    }
+}